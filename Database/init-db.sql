@@ -15,7 +15,12 @@ CREATE TABLE tables (
   name VARCHAR(256) NOT NULL,
   time_created TIMESTAMP NOT NULL,
   width INTEGER NOT NULL CHECK (width > 0),
-  height INTEGER NOT NULL CHECK (height > 0)
+  height INTEGER NOT NULL CHECK (height > 0),
+  -- Locking policy; NULL falls back to the WebSocket server's default
+  lock_duration_secs INTEGER CHECK (lock_duration_secs > 0),
+  max_locks_per_client INTEGER CHECK (max_locks_per_client > 0),
+  lock_granularity VARCHAR(16) CHECK (lock_granularity IN ('cell', 'row')),
+  max_exclusive_secs INTEGER CHECK (max_exclusive_secs > 0)
 );
 
 -- Stores individual text cells per table --
@@ -24,12 +29,29 @@ CREATE TABLE table_cells (
   row_num INTEGER NOT NULL CHECK (row_num >= 0),
   column_num INTEGER NOT NULL CHECK (column_num >= 0),
   text TEXT NOT NULL,
-  PRIMARY KEY (table_id, row_num, column_num)
+  -- Deferrable so that a single UPDATE may permute cell coordinates (e.g. when
+  -- moving rows); uniqueness is then checked at the end of the statement.
+  PRIMARY KEY (table_id, row_num, column_num) DEFERRABLE INITIALLY IMMEDIATE
 );
 
 -- Stores many-to-many relationship between users and shared tables --
 CREATE TABLE table_shares (
   user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
   table_id BIGINT NOT NULL REFERENCES tables(id) ON DELETE CASCADE,
+  -- Permission level of the shared user; the table owner is implicitly 'owner'
+  role VARCHAR(16) NOT NULL DEFAULT 'editor'
+    CHECK (role IN ('viewer', 'commenter', 'editor', 'owner')),
   PRIMARY KEY (user_id, table_id)
 );
+
+-- Stores rectangles of cells that only designated users (and the table owner)
+-- may edit; bounds are inclusive --
+CREATE TABLE protected_ranges (
+  id BIGSERIAL PRIMARY KEY,
+  table_id BIGINT NOT NULL REFERENCES tables(id) ON DELETE CASCADE,
+  top_row INTEGER NOT NULL CHECK (top_row >= 0),
+  left_col INTEGER NOT NULL CHECK (left_col >= 0),
+  bottom_row INTEGER NOT NULL CHECK (bottom_row >= top_row),
+  right_col INTEGER NOT NULL CHECK (right_col >= left_col),
+  allowed_user_ids BIGINT[] NOT NULL DEFAULT '{}'
+);
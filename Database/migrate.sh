@@ -0,0 +1,12 @@
+#!/bin/sh
+# Brings the database up to date by applying every migration in the migrations
+# directory next to this script, in order, each in its own transaction.
+# init-db.sql only runs when the database volume is first created; the
+# migrations are idempotent, so they are all applied again on every start.
+set -eu
+
+for migration in "$(dirname "$0")"/migrations/*.sql; do
+  echo "Applying $(basename "$migration")"
+  psql -v ON_ERROR_STOP=1 --single-transaction -q \
+    -h "$POSTGRES_HOST" -U "$POSTGRES_USER" -d "$POSTGRES_DB" -f "$migration"
+done
@@ -0,0 +1,15 @@
+-- Makes the cell key deferrable, so that moving rows and columns can permute
+-- cell coordinates in a single UPDATE --
+DO $$
+BEGIN
+  IF NOT EXISTS (
+    SELECT 1 FROM pg_constraint
+    WHERE conrelid = 'table_cells'::regclass AND contype = 'p' AND condeferrable
+  ) THEN
+    ALTER TABLE table_cells DROP CONSTRAINT IF EXISTS table_cells_pkey;
+    ALTER TABLE table_cells
+      ADD CONSTRAINT table_cells_pkey
+      PRIMARY KEY (table_id, row_num, column_num) DEFERRABLE INITIALLY IMMEDIATE;
+  END IF;
+END
+$$;
@@ -0,0 +1,4 @@
+-- Permission level of shared users; existing shares keep full edit access --
+ALTER TABLE table_shares
+  ADD COLUMN IF NOT EXISTS role VARCHAR(16) NOT NULL DEFAULT 'editor'
+    CHECK (role IN ('viewer', 'commenter', 'editor', 'owner'));
@@ -0,0 +1,11 @@
+-- Rectangles of cells that only designated users (and the table owner) may
+-- edit; bounds are inclusive --
+CREATE TABLE IF NOT EXISTS protected_ranges (
+  id BIGSERIAL PRIMARY KEY,
+  table_id BIGINT NOT NULL REFERENCES tables(id) ON DELETE CASCADE,
+  top_row INTEGER NOT NULL CHECK (top_row >= 0),
+  left_col INTEGER NOT NULL CHECK (left_col >= 0),
+  bottom_row INTEGER NOT NULL CHECK (bottom_row >= top_row),
+  right_col INTEGER NOT NULL CHECK (right_col >= left_col),
+  allowed_user_ids BIGINT[] NOT NULL DEFAULT '{}'
+);
@@ -0,0 +1,5 @@
+-- Locking policy of tables; NULL falls back to the WebSocket server's default --
+ALTER TABLE tables
+  ADD COLUMN IF NOT EXISTS lock_duration_secs INTEGER CHECK (lock_duration_secs > 0),
+  ADD COLUMN IF NOT EXISTS max_locks_per_client INTEGER CHECK (max_locks_per_client > 0),
+  ADD COLUMN IF NOT EXISTS lock_granularity VARCHAR(16) CHECK (lock_granularity IN ('cell', 'row'));
@@ -0,0 +1,4 @@
+-- How long exclusive locks on a table last; NULL falls back to the WebSocket
+-- server's default --
+ALTER TABLE tables
+  ADD COLUMN IF NOT EXISTS max_exclusive_secs INTEGER CHECK (max_exclusive_secs > 0);
@@ -11,6 +11,7 @@ import React, { useState, useEffect, useRef } from 'react';
 import { Plus } from 'lucide-react';
 
 import { useWebSocket } from '@/context/WebSocketContext';
+import { useAuth } from '@/context/AuthContext';
 import { TableCell as CellComponent } from './TableCell';
 
 import type TableProps from '@/types/TableProps';
@@ -86,13 +87,15 @@ export const TableEditor: React.FC<TableEditorProps> = (props: TableEditorProps)
   const { tableInfo } = props;
   const { id: tableId } = tableInfo;
   const { socket, connect, isConnected } = useWebSocket();
+  const { getAuthToken } = useAuth();
   const [table, setTable] = useState<TableCellData[][]>(
     Array.from({ length: 3 }, () => Array(3).fill({ text: '', owner_id: -1 }))
   );
   const [clientId, setClientId] = useState<number>(-1);
   const clientIdRef = useRef<number>(clientId);
   const wsScheme = window.location.protocol === 'https:' ? 'wss' : 'ws';
-  const wsUri = `${wsScheme}://${window.location.host}/ws/${tableId}`;
+  // Text offsets are computed on JavaScript strings, i.e. in UTF-16 code units
+  const wsUri = `${wsScheme}://${window.location.host}/ws/${tableId}?offset_unit=utf16`;
 
   useEffect(() => {
     clientIdRef.current = clientId;
@@ -195,7 +198,9 @@ export const TableEditor: React.FC<TableEditorProps> = (props: TableEditorProps)
 
   useEffect(() => {
     if (!isConnected) {
-      connect(wsUri, handleMessage);
+      // Browsers cannot set an Authorization header on a WebSocket, so the token
+      // is passed to the server as a subprotocol.
+      connect(wsUri, handleMessage, ['bearer', getAuthToken() ?? '']);
     }
   }, [isConnected, connect]);
 
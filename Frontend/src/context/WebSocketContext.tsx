@@ -7,7 +7,7 @@
 import React, { createContext, useContext, useRef, useState, useCallback } from 'react';
 
 interface WebSocketContextType {
-  connect: (uri: string, onMessage: (ev: MessageEvent) => void) => void;
+  connect: (uri: string, onMessage: (ev: MessageEvent) => void, protocols?: string[]) => void;
   isConnected: boolean;
   socket: WebSocket | null;
 }
@@ -18,11 +18,11 @@ export const WebSocketProvider: React.FC<{ children: React.ReactNode }> = ({ chi
   const socketRef = useRef<WebSocket | null>(null);
   const [connected, setConnected] = useState(false);
 
-  const connect = useCallback((uri: string, onMessage: (ev: MessageEvent) => void) => {
+  const connect = useCallback((uri: string, onMessage: (ev: MessageEvent) => void, protocols?: string[]) => {
     if (socketRef.current) {
       socketRef.current.close();
     }
-    const ws = new WebSocket(uri);
+    const ws = new WebSocket(uri, protocols);
     ws.onopen = () => setConnected(true);
     ws.onclose = () => {
       setConnected(false);
@@ -34,11 +34,55 @@ export interface DiffNone {
 
 export type StrDiff = DiffInsert | DiffReplace | DiffDelete | DiffNone;
 
+// Unit in which text offsets are expressed; negotiated with the offset_unit
+// query parameter when connecting.
+export type OffsetUnit = "byte" | "utf16" | "scalar" | "grapheme";
+
+// Only editors and owners may send mutating messages
+export type Role = "viewer" | "commenter" | "editor" | "owner";
+
+// Cells within [top_left, bottom_right] (inclusive, as [row, col]) may only be
+// edited by the listed users and the table owner
+export interface ProtectedRange {
+  id: number;
+  top_left: [number, number];
+  bottom_right: [number, number];
+  allowed_user_ids: number[];
+};
+
+// How the cells of a table are locked; editing or locking a cell locks its
+// whole row under "row" granularity
+export type LockGranularity = "cell" | "row";
+
+export interface LockPolicy {
+  lock_duration_secs: number;
+  max_locks_per_client: number | null;
+  granularity: LockGranularity;
+  max_exclusive_secs: number;
+};
+
+// The part of a table covered by an exclusive lock
+export type ExclusiveScope = { kind: "table" } | { kind: "row"; row: number };
+
+export interface ExclusiveLockView {
+  client_id: number;
+  scope: ExclusiveScope;
+  expires_in_secs: number;
+};
+
 // === Server-to-Client messages ===============================================
+export type DatabaseStatus = "available" | "unavailable";
+
 export interface ServerMessageInit {
   type: "init";
   client_id: number;
+  role: Role;
+  offset_unit: OffsetUnit;
+  lock_policy: LockPolicy;
   table: TableCellData[][];
+  protected_ranges: ProtectedRange[];
+  exclusive_locks: ExclusiveLockView[];
+  database_status: DatabaseStatus;
 };
 
 export interface ServerMessageInsert extends DiffInsert {
@@ -70,20 +114,123 @@ export interface ServerMessageInsertCols {
   num_cols: number;
 }
 
+export interface ServerMessageDeleteRows {
+  type: "delete_rows";
+  client_id: number;
+  start: number;
+  count: number;
+}
+
+export interface ServerMessageDeleteCols {
+  type: "delete_cols";
+  client_id: number;
+  start: number;
+  count: number;
+}
+
+export interface ServerMessageMoveRows {
+  type: "move_rows";
+  client_id: number;
+  from: number;
+  count: number;
+  to: number;
+}
+
+export interface ServerMessageMoveCols {
+  type: "move_cols";
+  client_id: number;
+  from: number;
+  count: number;
+  to: number;
+}
+
 export interface ServerMessageAcquireLock {
   type: "acquire_lock";
   client_id: number;
   cell: [number, number];
 };
 
+// The lock on the cell was handed to the first client queued for it (an
+// acquire_lock message is sent as well)
+export interface ServerMessageLockGranted {
+  type: "lock_granted";
+  client_id: number;
+  cell: [number, number];
+};
+
+// Every cell in [top_left, bottom_right] has been locked by the client; the
+// cells are released individually with release_lock messages
+export interface ServerMessageLockRange {
+  type: "lock_range";
+  client_id: number;
+  range_id: number;
+  top_left: [number, number];
+  bottom_right: [number, number];
+};
+
 export interface ServerMessageReleaseLock {
   type: "release_lock";
   cell: [number, number];
 };
 
+// Sent only to the client that made the request
+export interface ServerMessageAck {
+  type: "ack";
+  request_id: number;
+};
+
+export type ErrorCode =
+  | "lock_conflict"
+  | "lock_limit_exceeded"
+  | "out_of_range"
+  | "invalid_range"
+  | "invalid_operation"
+  | "forbidden"
+  | "parse_error"
+  | "storage_error";
+
+export interface ServerMessageError {
+  type: "error";
+  request_id: number | null;
+  code: ErrorCode;
+  message: string;
+};
+
+export interface ServerMessageExclusiveAcquired extends ExclusiveLockView {
+  type: "exclusive_acquired";
+};
+
+// broken_by is set when a table owner broke the lock
+export interface ServerMessageExclusiveReleased {
+  type: "exclusive_released";
+  client_id: number;
+  scope: ExclusiveScope;
+  broken_by: number | null;
+};
+
+// The client's last connection to the table closed; its locks have been
+// released
+export interface ServerMessageClientLeft {
+  type: "client_left";
+  client_id: number;
+};
+
+export interface ServerMessageServerShutdown {
+  type: "server_shutdown";
+  reconnect_after_ms: number;
+};
+
+export interface ServerMessageDatabaseStatus {
+  type: "database_status";
+  status: DatabaseStatus;
+};
+
 export type ServerStringMutateMessage = ServerMessageInsert | ServerMessageDelete | ServerMessageReplace | ServerMessageAcquireLock;
 export type ServerCellMutateMessage = ServerStringMutateMessage | ServerMessageReleaseLock;
-export type ServerMessage = ServerMessageInit | ServerCellMutateMessage | ServerMessageInsertRows | ServerMessageInsertCols;
+export type ServerMessage = ServerMessageInit | ServerCellMutateMessage | ServerMessageInsertRows | ServerMessageInsertCols
+  | ServerMessageDeleteRows | ServerMessageDeleteCols | ServerMessageMoveRows | ServerMessageMoveCols
+  | ServerMessageLockGranted | ServerMessageLockRange | ServerMessageExclusiveAcquired | ServerMessageExclusiveReleased
+  | ServerMessageClientLeft | ServerMessageServerShutdown | ServerMessageDatabaseStatus | ServerMessageAck | ServerMessageError;
 
 // === Client-to-Server messages ===============================================
 export interface ClientMessageInsert extends DiffInsert {
@@ -110,6 +257,90 @@ export interface ClientMessageInsertCols {
   num_cols: number;
 }
 
+export interface ClientMessageDeleteRows {
+  type: "delete_rows";
+  start: number;
+  count: number;
+}
+
+export interface ClientMessageDeleteCols {
+  type: "delete_cols";
+  start: number;
+  count: number;
+}
+
+// Moves the block [from, from + count) so that its first row/column ends up at
+// index `to` of the resulting table.
+export interface ClientMessageMoveRows {
+  type: "move_rows";
+  from: number;
+  count: number;
+  to: number;
+}
+
+export interface ClientMessageMoveCols {
+  type: "move_cols";
+  from: number;
+  count: number;
+  to: number;
+}
+
+// Explicit cell locks; a held lock expires unless renewed
+export interface ClientMessageAcquireLock {
+  type: "acquire_lock";
+  cell: [number, number];
+}
+
+export interface ClientMessageReleaseLock {
+  type: "release_lock";
+  cell: [number, number];
+}
+
+export interface ClientMessageRenewLock {
+  type: "renew_lock";
+  cell: [number, number];
+}
+
+// Acquires the lock on the cell if it is free, and otherwise queues for it;
+// release_lock leaves the queue
+export interface ClientMessageQueueLock {
+  type: "queue_lock";
+  cell: [number, number];
+}
+
+// Forcibly releases the lock on the cell (table owners only)
+export interface ClientMessageBreakLock {
+  type: "break_lock";
+  cell: [number, number];
+}
+
+// Locks every cell in [top_left, bottom_right], or none of them
+export interface ClientMessageLockRange {
+  type: "lock_range";
+  top_left: [number, number];
+  bottom_right: [number, number];
+}
+
+// Takes sole editing rights over the scope; table owners may force it to break
+// other clients' exclusive locks
+export interface ClientMessageAcquireExclusive {
+  type: "acquire_exclusive";
+  scope: ExclusiveScope;
+  force?: boolean;
+}
+
+export interface ClientMessageReleaseExclusive {
+  type: "release_exclusive";
+  scope: ExclusiveScope;
+}
+
 export type ClientStringMutateMessage = ClientMessageInsert | ClientMessageDelete | ClientMessageReplace;
-export type ClientCellMutateMessage = ClientStringMutateMessage;
-export type ClientMessage = ClientCellMutateMessage | ClientMessageInsertRows | ClientMessageInsertCols;
+export type ClientCellMutateMessage = ClientStringMutateMessage | ClientMessageAcquireLock | ClientMessageReleaseLock
+  | ClientMessageRenewLock | ClientMessageQueueLock | ClientMessageBreakLock;
+export type ClientMessage = ClientCellMutateMessage | ClientMessageInsertRows | ClientMessageInsertCols
+  | ClientMessageDeleteRows | ClientMessageDeleteCols | ClientMessageMoveRows | ClientMessageMoveCols
+  | ClientMessageLockRange | ClientMessageAcquireExclusive | ClientMessageReleaseExclusive;
+
+// Any client message may carry a request id, which the server echoes back in
+// the corresponding ack or error message.
+export type ClientRequest = ClientMessage & { request_id?: number };
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use log::error;
+
+use crate::{
+    TableId,
+    database::{Database, DatabaseError}
+};
+
+// How often an open connection re-checks the role its user holds on the table, so that revoking or
+// downgrading a share takes effect for collaborators who are already editing.
+pub(crate) const ACCESS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// === Role =======================================================================================
+//
+// The permission level a user holds on a table. The table owner always holds `owner`; other users
+// hold the role recorded on their row in table_shares. Roles are ordered from least to most
+// privileged.
+//
+// - viewer: May open the table and receive updates
+// - commenter: As viewer; cell comments are not yet supported, so this grants no edits
+// - editor: May edit cell text and the table structure
+// - owner: As editor
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    Viewer,
+    Commenter,
+    Editor,
+    Owner,
+}
+
+impl Role {
+    // Parses the value of table_shares.role
+    fn from_db(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "commenter" => Some(Role::Commenter),
+            "editor" => Some(Role::Editor),
+            "owner" => Some(Role::Owner),
+            _ => None
+        }
+    }
+
+    pub(crate) fn can_edit(self) -> bool {
+        self >= Role::Editor
+    }
+}
+
+// === table_role =================================================================================
+//
+// Returns the role the given user holds on the table, or None if the user neither owns the table
+// nor has had it shared with them.
+//
+// ================================================================================================
+pub(crate) async fn table_role(db: &Database, table_id: TableId, user_id: u64) -> Result<Option<Role>, DatabaseError> {
+    // Postgres BIGINT; ids that do not fit cannot belong to any user
+    let Ok(user_id) = i64::try_from(user_id) else {
+        return Ok(None);
+    };
+
+    let row = db.client().await?.query_opt(
+        "SELECT CASE WHEN owner_id = $2 THEN 'owner'
+                ELSE (SELECT role FROM table_shares WHERE table_id = $1 AND user_id = $2) END
+            FROM tables WHERE id = $1",
+        &[&table_id, &user_id]
+    ).await?;
+    let role = row.and_then(|row| row.get::<_, Option<String>>(0));
+
+    Ok(role.and_then(|role| {
+        let parsed = Role::from_db(&role);
+
+        if parsed.is_none() {
+            error!("unknown role {:?} on table {} for user {}", role, table_id, user_id);
+        }
+        parsed
+    }))
+}
@@ -0,0 +1,153 @@
+use std::{
+    error::Error,
+    fmt
+};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+// Subprotocol under which browsers pass the token in the Sec-WebSocket-Protocol header, as
+// `Sec-WebSocket-Protocol: bearer, <token>`. Browsers cannot set an Authorization header on a
+// WebSocket upgrade.
+pub(crate) const BEARER_PROTOCOL: &str = "bearer";
+
+// === Claims =====================================================================================
+//
+// Claims carried by the JWTs the REST API issues (see JwtUtil.generateToken). The expiration
+// claim is checked by jsonwebtoken itself.
+//
+// - sub: The username of the authenticated user
+// - uid: The id of the authenticated user
+//
+// ================================================================================================
+#[derive(Debug, Clone, Deserialize)]
+struct Claims {
+    sub: String,
+    uid: i64
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AuthenticatedUser {
+    pub(crate) user_id: u64,
+    pub(crate) username: String
+}
+
+#[derive(Debug)]
+pub(crate) enum AuthError {
+    MissingToken,
+    InvalidToken(jsonwebtoken::errors::Error),
+    InvalidUserId(i64)
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "no token provided"),
+            AuthError::InvalidToken(e) => write!(f, "invalid token: {}", e),
+            AuthError::InvalidUserId(uid) => write!(f, "invalid user id in token: {}", uid)
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+// === JwtVerifier ================================================================================
+//
+// Verifies tokens issued by the REST API.
+//
+// The REST API signs tokens with HS256 using jjwt's signWith(SignatureAlgorithm, String), which
+// treats JWT_SECRET as a base64-encoded key, so the secret is base64-decoded here as well.
+//
+// ================================================================================================
+pub(crate) struct JwtVerifier {
+    key: DecodingKey,
+    validation: Validation
+}
+
+impl JwtVerifier {
+    pub(crate) fn from_base64_secret(secret: &str) -> Result<Self, jsonwebtoken::errors::Error> {
+        let mut validation = Validation::new(Algorithm::HS256);
+
+        validation.set_required_spec_claims(&["exp", "sub"]);
+
+        Ok(Self { key: DecodingKey::from_base64_secret(secret)?, validation })
+    }
+
+    pub(crate) fn verify(&self, token: Option<&str>) -> Result<AuthenticatedUser, AuthError> {
+        let token = token.ok_or(AuthError::MissingToken)?;
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(AuthError::InvalidToken)?
+            .claims;
+        let user_id = u64::try_from(claims.uid).map_err(|_| AuthError::InvalidUserId(claims.uid))?;
+
+        Ok(AuthenticatedUser { user_id, username: claims.sub })
+    }
+}
+
+// Extracts the token from a `Sec-WebSocket-Protocol: bearer, <token>` header value.
+pub(crate) fn token_from_protocol_header(header: &str) -> Option<&str> {
+    let mut protocols = header.split(',').map(str::trim);
+
+    match (protocols.next(), protocols.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case(BEARER_PROTOCOL) && !token.is_empty() => Some(token),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+
+    // base64 of 64 random-looking bytes, as JWT_SECRET would be configured
+    const SECRET: &str = "c2VjcmV0LXNlY3JldC1zZWNyZXQtc2VjcmV0LXNlY3JldC1zZWNyZXQtc2VjcmV0LXNlY3JldC1zZWNyZXQ=";
+
+    #[derive(Serialize)]
+    struct TestClaims {
+        sub: &'static str,
+        uid: i64,
+        iat: u64,
+        exp: u64
+    }
+
+    fn sign(secret: &str, uid: i64, expires_in_secs: i64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let claims = TestClaims { sub: "alice", uid, iat: now, exp: now.saturating_add_signed(expires_in_secs) };
+
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_base64_secret(secret).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn accepts_tokens_signed_with_the_shared_secret() {
+        let verifier = JwtVerifier::from_base64_secret(SECRET).unwrap();
+        let user = verifier.verify(Some(&sign(SECRET, 42, 600))).unwrap();
+
+        assert_eq!(user.user_id, 42);
+        assert_eq!(user.username, "alice");
+    }
+
+    #[test]
+    fn rejects_missing_expired_and_foreign_tokens() {
+        let verifier = JwtVerifier::from_base64_secret(SECRET).unwrap();
+        let other_secret = "b3RoZXItb3RoZXItb3RoZXItb3RoZXItb3RoZXItb3RoZXItb3RoZXItb3RoZXI=";
+
+        assert!(matches!(verifier.verify(None), Err(AuthError::MissingToken)));
+        assert!(matches!(verifier.verify(Some("not.a.token")), Err(AuthError::InvalidToken(_))));
+        assert!(matches!(verifier.verify(Some(&sign(SECRET, 42, -3600))), Err(AuthError::InvalidToken(_))));
+        assert!(matches!(verifier.verify(Some(&sign(other_secret, 42, 600))), Err(AuthError::InvalidToken(_))));
+        assert!(matches!(verifier.verify(Some(&sign(SECRET, -1, 600))), Err(AuthError::InvalidUserId(-1))));
+    }
+
+    #[test]
+    fn extracts_token_from_protocol_header() {
+        assert_eq!(token_from_protocol_header("bearer, abc.def.ghi"), Some("abc.def.ghi"));
+        assert_eq!(token_from_protocol_header("Bearer,abc"), Some("abc"));
+        assert_eq!(token_from_protocol_header("bearer"), None);
+        assert_eq!(token_from_protocol_header("bearer, "), None);
+        assert_eq!(token_from_protocol_header("chat, abc"), None);
+    }
+}
@@ -0,0 +1,580 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration
+};
+
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::{
+    database::DatabaseConfig,
+    locking::{LockGranularity, LockPolicy},
+    persistence::WriteBehindPolicy,
+    residency::ResidencyPolicy,
+    shutdown::ShutdownPolicy,
+    tls::{DatabaseTlsConfig, ServerTlsConfig, SslMode}
+};
+
+// === Config =====================================================================================
+//
+// The settings of the server, validated and with defaults filled in.
+//
+// Each setting is taken from the first of these that sets it:
+//  1. A command-line flag, e.g. --port 4000
+//  2. An environment variable, e.g. TABLE_EDITOR_WS_PORT=4000
+//  3. The TOML file given by --config or TABLE_EDITOR_CONFIG, e.g. `port = 4000` under `[server]`
+//  4. The default
+//
+// Run the server with --help for the full list of flags and variables.
+//
+// ================================================================================================
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub(crate) listen_addr: SocketAddr,
+    // The certificate to serve wss:// with; None to serve plain ws://, e.g. behind a proxy
+    pub(crate) tls: Option<ServerTlsConfig>,
+    pub(crate) log_level: LevelFilter,
+    pub(crate) database: DatabaseConfig,
+    // Capacity of each table's broadcast channel; clients that fall further behind miss messages
+    pub(crate) broadcast_capacity: usize,
+    // Lock settings for tables that do not set their own
+    pub(crate) lock_defaults: LockPolicy,
+    pub(crate) residency: ResidencyPolicy,
+    pub(crate) write_behind: WriteBehindPolicy,
+    pub(crate) journal_dir: PathBuf,
+    pub(crate) shutdown: ShutdownPolicy
+}
+
+// Every setting that failed validation, or why the configuration file could not be read
+#[derive(Debug)]
+pub(crate) struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// The settings as given on the command line and in the environment
+#[derive(Debug, Default, Parser)]
+#[command(about = "Serves table editing sessions over WebSockets")]
+struct Args {
+    /// TOML file to read settings from
+    #[arg(long, env = "TABLE_EDITOR_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    settings: Settings
+}
+
+// The settings as given in the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    log_level: Option<LevelFilter>,
+    server: ServerSettings,
+    database: DatabaseSettings,
+    channels: ChannelSettings,
+    locks: LockSettings,
+    residency: ResidencySettings,
+    write_behind: WriteBehindSettings,
+    shutdown: ShutdownSettings
+}
+
+// The command-line flags and the sections of the configuration file share their fields; every
+// field is optional until the sources have been merged
+#[derive(Debug, Default, clap::Args)]
+struct Settings {
+    /// Maximum level of log messages: off, error, warn, info, debug or trace [default: info]
+    #[arg(long, env = "TABLE_EDITOR_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+
+    #[command(flatten)]
+    server: ServerSettings,
+
+    #[command(flatten)]
+    database: DatabaseSettings,
+
+    #[command(flatten)]
+    channels: ChannelSettings,
+
+    #[command(flatten)]
+    locks: LockSettings,
+
+    #[command(flatten)]
+    residency: ResidencySettings,
+
+    #[command(flatten)]
+    write_behind: WriteBehindSettings,
+
+    #[command(flatten)]
+    shutdown: ShutdownSettings
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSettings {
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long = "host", env = "TABLE_EDITOR_WS_HOST")]
+    host: Option<IpAddr>,
+
+    /// Port to listen on [default: 3000]
+    #[arg(long = "port", env = "TABLE_EDITOR_WS_PORT")]
+    port: Option<u16>,
+
+    /// PEM certificate chain to serve wss:// with; plain ws:// is served if unset
+    #[arg(long = "tls-cert", env = "TABLE_EDITOR_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate
+    #[arg(long = "tls-key", env = "TABLE_EDITOR_TLS_KEY")]
+    tls_key: Option<PathBuf>
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSettings {
+    /// Database host [default: database]
+    #[arg(id = "db_host", value_name = "HOST", long = "db-host", env = "POSTGRES_HOST")]
+    host: Option<String>,
+
+    /// Database port [default: 5432]
+    #[arg(id = "db_port", value_name = "PORT", long = "db-port", env = "POSTGRES_PORT")]
+    port: Option<u16>,
+
+    /// Database user (required)
+    #[arg(id = "db_user", value_name = "USER", long = "db-user", env = "POSTGRES_USER")]
+    user: Option<String>,
+
+    /// Database name (required)
+    #[arg(id = "db_name", value_name = "NAME", long = "db-name", env = "POSTGRES_DB")]
+    dbname: Option<String>,
+
+    /// Database password (required)
+    #[arg(id = "db_password", value_name = "PASSWORD", long = "db-password", env = "POSTGRES_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Maximum number of open database connections [default: 16]
+    #[arg(id = "db_pool_size", value_name = "POOL_SIZE", long = "db-pool-size", env = "TABLE_EDITOR_DB_POOL_SIZE")]
+    pool_size: Option<usize>,
+
+    /// Seconds between database health checks [default: 5]
+    #[arg(id = "db_health_check_secs", value_name = "HEALTH_CHECK_SECS", long = "db-health-check-secs", env = "TABLE_EDITOR_DB_HEALTH_CHECK_SECS")]
+    health_check_secs: Option<u64>,
+
+    /// Whether the database connection is encrypted, and its certificate verified [default: disable]
+    #[arg(id = "db_sslmode", value_name = "SSLMODE", long = "db-sslmode", env = "PGSSLMODE")]
+    sslmode: Option<SslMode>,
+
+    /// PEM bundle of the CAs trusted to sign the database's certificate [default: the system's CAs]
+    #[arg(id = "db_sslrootcert", value_name = "SSLROOTCERT", long = "db-sslrootcert", env = "PGSSLROOTCERT")]
+    sslrootcert: Option<PathBuf>,
+
+    /// PEM client certificate presented to the database
+    #[arg(id = "db_sslcert", value_name = "SSLCERT", long = "db-sslcert", env = "PGSSLCERT")]
+    sslcert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(id = "db_sslkey", value_name = "SSLKEY", long = "db-sslkey", env = "PGSSLKEY")]
+    sslkey: Option<PathBuf>
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+struct ChannelSettings {
+    /// Messages buffered per table for slow clients [default: 100]
+    #[arg(long = "broadcast-capacity", env = "TABLE_EDITOR_BROADCAST_CAPACITY")]
+    broadcast_capacity: Option<usize>
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+struct LockSettings {
+    /// Seconds a cell lock is held after the last edit, for tables without their own setting
+    /// [default: 3]
+    #[arg(long = "lock-duration-secs", env = "TABLE_EDITOR_LOCK_DURATION_SECS")]
+    lock_duration_secs: Option<u32>,
+
+    /// Locks a client may hold at once, for tables without their own setting [default: unlimited]
+    #[arg(long = "max-locks-per-client", env = "TABLE_EDITOR_MAX_LOCKS_PER_CLIENT")]
+    max_locks_per_client: Option<u32>,
+
+    /// Unit in which cells are locked, for tables without their own setting [default: cell]
+    #[arg(long = "lock-granularity", env = "TABLE_EDITOR_LOCK_GRANULARITY")]
+    granularity: Option<LockGranularity>,
+
+    /// Seconds an exclusive lock may be held, for tables without their own setting [default: 300]
+    #[arg(long = "max-exclusive-secs", env = "TABLE_EDITOR_MAX_EXCLUSIVE_SECS")]
+    max_exclusive_secs: Option<u32>
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+struct ResidencySettings {
+    /// Seconds a table without clients stays in memory [default: 60]
+    #[arg(long = "idle-table-grace-secs", env = "TABLE_EDITOR_IDLE_TABLE_GRACE_SECS")]
+    idle_table_grace_secs: Option<u64>,
+
+    /// Maximum number of tables held in memory while idle [default: 256]
+    #[arg(long = "max-resident-tables", env = "TABLE_EDITOR_MAX_RESIDENT_TABLES")]
+    max_resident_tables: Option<usize>
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+struct WriteBehindSettings {
+    /// Milliseconds edits may wait before being written to the database [default: 500]
+    #[arg(long = "flush-interval-ms", env = "TABLE_EDITOR_FLUSH_INTERVAL_MS")]
+    flush_interval_ms: Option<u64>,
+
+    /// Edited cells that trigger an early write [default: 256]
+    #[arg(long = "flush-max-dirty-cells", env = "TABLE_EDITOR_FLUSH_MAX_DIRTY_CELLS")]
+    max_dirty_cells: Option<usize>,
+
+    /// Directory for the journal of edits not yet written to the database [default: journal]
+    #[arg(long = "journal-dir", env = "TABLE_EDITOR_JOURNAL_DIR")]
+    journal_dir: Option<PathBuf>
+}
+
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+struct ShutdownSettings {
+    /// Milliseconds clients are asked to wait before reconnecting after a shutdown [default: 5000]
+    #[arg(long = "reconnect-after-ms", env = "TABLE_EDITOR_RECONNECT_AFTER_MS")]
+    reconnect_after_ms: Option<u64>,
+
+    /// Seconds shutdown may take before the server exits regardless [default: 8]
+    #[arg(long = "shutdown-deadline-secs", env = "TABLE_EDITOR_SHUTDOWN_DEADLINE_SECS")]
+    deadline_secs: Option<u64>
+}
+
+impl Settings {
+    // Fills in the settings left unset from another source
+    fn or(self, file: ConfigFile) -> Self {
+        let Self { log_level, server, database, channels, locks, residency, write_behind, shutdown } = self;
+
+        Self {
+            log_level: log_level.or(file.log_level),
+            server: ServerSettings {
+                host: server.host.or(file.server.host),
+                port: server.port.or(file.server.port),
+                tls_cert: server.tls_cert.or(file.server.tls_cert),
+                tls_key: server.tls_key.or(file.server.tls_key)
+            },
+            database: DatabaseSettings {
+                host: database.host.or(file.database.host),
+                port: database.port.or(file.database.port),
+                user: database.user.or(file.database.user),
+                dbname: database.dbname.or(file.database.dbname),
+                password: database.password.or(file.database.password),
+                pool_size: database.pool_size.or(file.database.pool_size),
+                health_check_secs: database.health_check_secs.or(file.database.health_check_secs),
+                sslmode: database.sslmode.or(file.database.sslmode),
+                sslrootcert: database.sslrootcert.or(file.database.sslrootcert),
+                sslcert: database.sslcert.or(file.database.sslcert),
+                sslkey: database.sslkey.or(file.database.sslkey)
+            },
+            channels: ChannelSettings {
+                broadcast_capacity: channels.broadcast_capacity.or(file.channels.broadcast_capacity)
+            },
+            locks: LockSettings {
+                lock_duration_secs: locks.lock_duration_secs.or(file.locks.lock_duration_secs),
+                max_locks_per_client: locks.max_locks_per_client.or(file.locks.max_locks_per_client),
+                granularity: locks.granularity.or(file.locks.granularity),
+                max_exclusive_secs: locks.max_exclusive_secs.or(file.locks.max_exclusive_secs)
+            },
+            residency: ResidencySettings {
+                idle_table_grace_secs: residency.idle_table_grace_secs.or(file.residency.idle_table_grace_secs),
+                max_resident_tables: residency.max_resident_tables.or(file.residency.max_resident_tables)
+            },
+            write_behind: WriteBehindSettings {
+                flush_interval_ms: write_behind.flush_interval_ms.or(file.write_behind.flush_interval_ms),
+                max_dirty_cells: write_behind.max_dirty_cells.or(file.write_behind.max_dirty_cells),
+                journal_dir: write_behind.journal_dir.or(file.write_behind.journal_dir)
+            },
+            shutdown: ShutdownSettings {
+                reconnect_after_ms: shutdown.reconnect_after_ms.or(file.shutdown.reconnect_after_ms),
+                deadline_secs: shutdown.deadline_secs.or(file.shutdown.deadline_secs)
+            }
+        }
+    }
+
+    // Fills in the defaults and checks every setting, reporting all problems at once
+    fn validate(self) -> Result<Config, ConfigError> {
+        let mut problems = vec![];
+        let mut positive = |name: &str, value: Option<u64>, default: u64| match value {
+            Some(0) => {
+                problems.push(format!("{} must be greater than 0", name));
+                default
+            },
+            value => value.unwrap_or(default)
+        };
+
+        let default_locks = LockPolicy::default();
+        let default_residency = ResidencyPolicy::default();
+        let default_write_behind = WriteBehindPolicy::default();
+        let default_shutdown = ShutdownPolicy::default();
+
+        let port = positive("port", self.server.port.map(u64::from), 3000) as u16;
+        let db_port = positive("db-port", self.database.port.map(u64::from), 5432) as u16;
+        let pool_size = positive("db-pool-size", self.database.pool_size.map(|n| n as u64), 16) as usize;
+        let health_check_secs = positive("db-health-check-secs", self.database.health_check_secs, 5);
+        let broadcast_capacity = positive("broadcast-capacity", self.channels.broadcast_capacity.map(|n| n as u64), 100) as usize;
+        let lock_duration_secs = positive(
+            "lock-duration-secs",
+            self.locks.lock_duration_secs.map(u64::from),
+            default_locks.lock_duration_secs.into()
+        ) as u32;
+        // Unset means unlimited, so only an explicit limit is checked
+        let max_locks_per_client = self.locks.max_locks_per_client
+            .map(|max_locks| positive("max-locks-per-client", Some(max_locks.into()), 1) as u32);
+        let max_exclusive_secs = positive(
+            "max-exclusive-secs",
+            self.locks.max_exclusive_secs.map(u64::from),
+            default_locks.max_exclusive_secs.into()
+        ) as u32;
+        let max_resident_tables = positive(
+            "max-resident-tables",
+            self.residency.max_resident_tables.map(|n| n as u64),
+            default_residency.max_resident_tables as u64
+        ) as usize;
+        let flush_interval_ms = positive(
+            "flush-interval-ms",
+            self.write_behind.flush_interval_ms,
+            default_write_behind.flush_interval.as_millis() as u64
+        );
+        let max_dirty_cells = positive(
+            "flush-max-dirty-cells",
+            self.write_behind.max_dirty_cells.map(|n| n as u64),
+            default_write_behind.max_dirty_cells as u64
+        ) as usize;
+        let shutdown_deadline_secs = positive(
+            "shutdown-deadline-secs",
+            self.shutdown.deadline_secs,
+            default_shutdown.deadline.as_secs()
+        );
+
+        let mut required = |name: &str, value: Option<String>| value.unwrap_or_else(|| {
+            problems.push(format!("{} is required", name));
+            String::new()
+        });
+
+        let user = required("db-user (POSTGRES_USER)", self.database.user);
+        let dbname = required("db-name (POSTGRES_DB)", self.database.dbname);
+        let password = required("db-password (POSTGRES_PASSWORD)", self.database.password);
+
+        let tls = DatabaseTlsConfig {
+            sslmode: self.database.sslmode.unwrap_or_default(),
+            sslrootcert: self.database.sslrootcert,
+            sslcert: self.database.sslcert,
+            sslkey: self.database.sslkey
+        };
+
+        let server_tls = match (self.server.tls_cert, self.server.tls_key) {
+            (Some(cert), Some(key)) => Some(ServerTlsConfig { cert, key }),
+            (None, None) => None,
+            _ => {
+                problems.push(String::from("tls-cert and tls-key must be set together"));
+                None
+            }
+        };
+
+        if tls.sslcert.is_some() != tls.sslkey.is_some() {
+            problems.push(String::from("db-sslcert and db-sslkey must be set together"));
+        }
+        if tls.sslcert.is_some() && tls.sslmode == SslMode::Disable {
+            problems.push(String::from("db-sslcert requires db-sslmode require or verify-full"));
+        }
+        if tls.sslrootcert.is_some() && tls.sslmode != SslMode::VerifyFull {
+            problems.push(String::from("db-sslrootcert requires db-sslmode verify-full"));
+        }
+
+        if !problems.is_empty() {
+            return Err(ConfigError(problems));
+        }
+
+        Ok(Config {
+            listen_addr: SocketAddr::new(self.server.host.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port),
+            tls: server_tls,
+            log_level: self.log_level.unwrap_or(LevelFilter::Info),
+            database: DatabaseConfig {
+                host: self.database.host.unwrap_or_else(|| String::from("database")),
+                port: db_port,
+                user,
+                dbname,
+                password,
+                pool_size,
+                health_check_interval: Duration::from_secs(health_check_secs),
+                tls
+            },
+            broadcast_capacity,
+            lock_defaults: LockPolicy {
+                lock_duration_secs,
+                max_locks_per_client: max_locks_per_client.or(default_locks.max_locks_per_client),
+                granularity: self.locks.granularity.unwrap_or(default_locks.granularity),
+                max_exclusive_secs
+            },
+            residency: ResidencyPolicy {
+                idle_grace: self.residency.idle_table_grace_secs
+                    .map(Duration::from_secs)
+                    .unwrap_or(default_residency.idle_grace),
+                max_resident_tables
+            },
+            write_behind: WriteBehindPolicy {
+                flush_interval: Duration::from_millis(flush_interval_ms),
+                max_dirty_cells
+            },
+            journal_dir: self.write_behind.journal_dir.unwrap_or_else(|| PathBuf::from("journal")),
+            shutdown: ShutdownPolicy {
+                reconnect_after: self.shutdown.reconnect_after_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(default_shutdown.reconnect_after),
+                deadline: Duration::from_secs(shutdown_deadline_secs)
+            }
+        })
+    }
+}
+
+impl Config {
+    // Reads the configuration from the command line, the environment and the configuration file.
+    // Exits with a usage message if the command line cannot be parsed.
+    pub(crate) fn load() -> Result<Self, ConfigError> {
+        Self::from_args(Args::parse())
+    }
+
+    fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError(vec![format!("could not read {}: {}", path.display(), e)]))?;
+
+                toml::from_str(&contents)
+                    .map_err(|e| ConfigError(vec![format!("could not parse {}: {}", path.display(), e)]))?
+            },
+            None => ConfigFile::default()
+        };
+
+        args.settings.or(file).validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    // Settings as parsed from the command line; built directly, since parsing would also pick up
+    // the environment of the test
+    fn settings_with_credentials() -> Settings {
+        Settings {
+            database: DatabaseSettings {
+                user: Some(String::from("u")),
+                dbname: Some(String::from("d")),
+                password: Some(String::from("p")),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn flags_are_well_formed() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let mut settings = settings_with_credentials();
+
+        settings.server.port = Some(4000);
+        settings.channels.broadcast_capacity = Some(8);
+
+        let file: ConfigFile = toml::from_str(r#"
+            log_level = "debug"
+
+            [server]
+            port = 5000
+
+            [database]
+            host = "db.internal"
+            pool_size = 4
+            sslmode = "verify-full"
+
+            [channels]
+            broadcast_capacity = 50
+
+            [locks]
+            max_locks_per_client = 10
+            granularity = "row"
+        "#).unwrap();
+
+        let config = settings.or(file).validate().unwrap();
+
+        assert_eq!(config.listen_addr, "0.0.0.0:4000".parse().unwrap());
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.broadcast_capacity, 8);
+        assert_eq!(config.database.host, "db.internal");
+        assert_eq!(config.database.pool_size, 4);
+        assert_eq!(config.database.port, 5432);
+        assert_eq!(config.database.tls.sslmode, SslMode::VerifyFull);
+        assert_eq!(config.write_behind, WriteBehindPolicy::default());
+        assert_eq!(config.lock_defaults.max_locks_per_client, Some(10));
+        assert_eq!(config.lock_defaults.granularity, LockGranularity::Row);
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        let mut settings = Settings::default();
+
+        settings.server.port = Some(0);
+        settings.database.user = Some(String::from("u"));
+        settings.write_behind.flush_interval_ms = Some(0);
+        settings.locks.max_locks_per_client = Some(0);
+
+        assert_eq!(settings.or(ConfigFile::default()).validate().unwrap_err().0, [
+            "port must be greater than 0",
+            "max-locks-per-client must be greater than 0",
+            "flush-interval-ms must be greater than 0",
+            "db-name (POSTGRES_DB) is required",
+            "db-password (POSTGRES_PASSWORD) is required"
+        ]);
+
+        let mut settings = settings_with_credentials();
+
+        settings.database.sslcert = Some(PathBuf::from("client.pem"));
+        settings.database.sslrootcert = Some(PathBuf::from("ca.pem"));
+        settings.server.tls_key = Some(PathBuf::from("key.pem"));
+
+        assert_eq!(settings.or(ConfigFile::default()).validate().unwrap_err().0, [
+            "tls-cert and tls-key must be set together",
+            "db-sslcert and db-sslkey must be set together",
+            "db-sslcert requires db-sslmode require or verify-full",
+            "db-sslrootcert requires db-sslmode verify-full"
+        ]);
+        assert!(toml::from_str::<ConfigFile>("[server]\nprot = 1").is_err());
+    }
+
+    #[test]
+    fn example_file_matches_the_defaults() {
+        let file: ConfigFile = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        let config = settings_with_credentials().or(file).validate().unwrap();
+
+        assert_eq!(config.lock_defaults, LockPolicy::default());
+        assert_eq!(config.residency, ResidencyPolicy::default());
+        assert_eq!(config.write_behind, WriteBehindPolicy::default());
+        assert_eq!(config.shutdown, ShutdownPolicy::default());
+        assert_eq!(config.database.tls, DatabaseTlsConfig::default());
+        assert_eq!(config.listen_addr, "0.0.0.0:3000".parse().unwrap());
+        assert_eq!(config.tls, None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::Arc,
+    time::Duration
+};
+
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod, Runtime, TimeoutType};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_postgres as postgres;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use log::{error, info, warn};
+
+use crate::tls::DatabaseTlsConfig;
+
+// The first delay before retrying an unreachable database; doubled after every failed attempt
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+// How long a request may wait for a connection before the database is considered unreachable
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// === DatabaseConfig =============================================================================
+//
+// Where the database is and how many connections are kept to it.
+//
+// - host, port, user, dbname, password: The connection parameters
+// - pool_size: The maximum number of connections held open at once
+// - health_check_interval: How often the database is checked while it is reachable
+// - tls: Whether and how connections are encrypted
+//
+// ================================================================================================
+#[derive(Debug, Clone)]
+pub(crate) struct DatabaseConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) user: String,
+    pub(crate) dbname: String,
+    pub(crate) password: String,
+    pub(crate) pool_size: usize,
+    pub(crate) health_check_interval: Duration,
+    pub(crate) tls: DatabaseTlsConfig
+}
+
+// === DatabaseStatus =============================================================================
+//
+// Whether the server can currently reach the database. While it cannot, edits are kept in memory
+// and written once it is back, but structural changes are rejected and tables that are not yet
+// loaded cannot be opened.
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DatabaseStatus {
+    Available,
+    Unavailable,
+}
+
+// Either no connection could be obtained, or a query failed
+#[derive(Debug)]
+pub(crate) enum DatabaseError {
+    Unavailable(PoolError),
+    Query(postgres::Error),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mut message, mut source) = match self {
+            DatabaseError::Unavailable(e) => (format!("database unavailable: {}", e), e.source()),
+            DatabaseError::Query(e) => (e.to_string(), e.source())
+        };
+
+        // The underlying cause, e.g. why a TLS handshake failed, is often only found in the source
+        while let Some(e) = source {
+            let cause = e.to_string();
+
+            if !message.contains(&cause) {
+                message = format!("{}: {}", message, cause);
+            }
+            source = e.source();
+        }
+        write!(f, "{}", message)
+    }
+}
+
+impl Error for DatabaseError {}
+
+impl From<PoolError> for DatabaseError {
+    fn from(e: PoolError) -> Self {
+        DatabaseError::Unavailable(e)
+    }
+}
+
+impl From<postgres::Error> for DatabaseError {
+    fn from(e: postgres::Error) -> Self {
+        DatabaseError::Query(e)
+    }
+}
+
+// === Database ===================================================================================
+//
+// A pool of connections to the database, shared by the whole server. Connections that were closed
+// (e.g. because Postgres restarted) are discarded and replaced on demand.
+//
+// ================================================================================================
+#[derive(Clone)]
+pub(crate) struct Database {
+    pool: Pool,
+    status: Arc<watch::Sender<DatabaseStatus>>
+}
+
+impl Database {
+    // Creates the pool. No connection is made until one is needed, so the server starts even
+    // while the database is unreachable.
+    pub(crate) fn new(config: &DatabaseConfig) -> Result<Self, Box<dyn Error>> {
+        let mut pg_config = postgres::Config::new();
+
+        pg_config
+            .host(&config.host)
+            .port(config.port)
+            .user(&config.user)
+            .dbname(&config.dbname)
+            .password(&config.password)
+            .connect_timeout(CONNECTION_TIMEOUT);
+
+        let manager_config = ManagerConfig { recycling_method: RecyclingMethod::Fast };
+        let manager = match config.tls.client_config()? {
+            Some(tls_config) => {
+                pg_config.ssl_mode(postgres::config::SslMode::Require);
+                Manager::from_config(pg_config, MakeRustlsConnect::new(tls_config), manager_config)
+            },
+            None => Manager::from_config(pg_config, postgres::NoTls, manager_config)
+        };
+        let pool = Pool::builder(manager)
+            .max_size(config.pool_size)
+            .runtime(Runtime::Tokio1)
+            .wait_timeout(Some(CONNECTION_TIMEOUT))
+            .create_timeout(Some(CONNECTION_TIMEOUT))
+            .build()?;
+
+        Ok(Self {
+            pool,
+            status: Arc::new(watch::channel(DatabaseStatus::Available).0)
+        })
+    }
+
+    // Takes a connection from the pool. Failing to connect marks the database as unavailable
+    // until the health monitor reaches it again; merely waiting too long for a busy pool does not.
+    pub(crate) async fn client(&self) -> Result<Object, DatabaseError> {
+        self.pool.get().await.map_err(|e| {
+            if matches!(e, PoolError::Backend(_) | PoolError::Timeout(TimeoutType::Create)) {
+                self.set_status(DatabaseStatus::Unavailable);
+            }
+            DatabaseError::from(e)
+        })
+    }
+
+    pub(crate) fn status(&self) -> watch::Receiver<DatabaseStatus> {
+        self.status.subscribe()
+    }
+
+    fn set_status(&self, status: DatabaseStatus) {
+        self.status.send_if_modified(|current| {
+            if *current == status {
+                return false;
+            }
+            match status {
+                DatabaseStatus::Available => info!("Database is available again"),
+                DatabaseStatus::Unavailable => error!("database is unavailable")
+            }
+            *current = status;
+            true
+        });
+    }
+
+    async fn check_health(&self) -> Result<(), DatabaseError> {
+        // Drop connections whose connection task has ended
+        self.pool.retain(|client, _| !client.is_closed());
+
+        self.pool.get().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+
+    // === run_health_monitor =====================================================================
+    //
+    // Checks the database at a regular interval while it is reachable, and with exponential
+    // backoff while it is not, updating the status reported to clients.
+    //
+    // ============================================================================================
+    pub(crate) async fn run_health_monitor(self, health_check_interval: Duration) {
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+
+        loop {
+            match self.check_health().await {
+                Ok(()) => {
+                    self.set_status(DatabaseStatus::Available);
+                    backoff = RECONNECT_BACKOFF_MIN;
+                    tokio::time::sleep(health_check_interval).await;
+                },
+                Err(e) => {
+                    self.set_status(DatabaseStatus::Unavailable);
+                    warn!("Retrying database connection in {:?} -- {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}
+};
+use log::{error, info};
+
+use crate::{
+    SharedTablesMap,
+    TableId,
+    database::{Database, DatabaseStatus},
+    persistence::write_cells,
+    residency::{claim_slot, release_slot}
+};
+
+const JOURNAL_EXTENSION: &str = "journal";
+
+// One accepted edit: the text of the cell once the edit was applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct JournalEntry {
+    cell: (usize, usize),
+    text: String
+}
+
+// === Journal ====================================================================================
+//
+// The directory holding the write-ahead journals of the tables, one file per table. Every edit is
+// appended to its table's journal, and the journal fsynced, before the edit is acknowledged. Once
+// the table's dirty cells have been written to the database the journal is truncated, so a journal
+// only ever holds edits the database may not have yet.
+//
+// Journals left behind by a crash or a database outage are replayed into the database on startup,
+// whenever the database becomes available again, and before their table is next loaded.
+//
+// ================================================================================================
+#[derive(Debug, Clone)]
+pub(crate) struct Journal {
+    dir: Arc<PathBuf>
+}
+
+impl Journal {
+    // Creates the journal directory if it does not exist yet
+    pub(crate) async fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+
+        fs::create_dir_all(&dir).await?;
+        Ok(Self { dir: Arc::new(dir) })
+    }
+
+    fn path(&self, table_id: TableId) -> PathBuf {
+        self.dir.join(format!("{}.{}", table_id, JOURNAL_EXTENSION))
+    }
+
+    // Opens the journal of a table for appending, creating it if necessary
+    pub(crate) async fn open(&self, table_id: TableId) -> io::Result<TableJournal> {
+        let path = self.path(table_id);
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        let len = file.metadata().await?.len();
+
+        // Make sure the file itself survives a crash, not only its contents
+        sync_dir(&self.dir).await?;
+
+        Ok(TableJournal { file, path, len })
+    }
+
+    // === replay =================================================================================
+    //
+    // Writes the edits in the journal of a table to the database, then deletes the journal. Does
+    // nothing if the table has no journal. The table must not be resident, or its journal would be
+    // deleted while still in use.
+    //
+    // ============================================================================================
+    pub(crate) async fn replay(&self, table_id: TableId, db: &Database) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = self.path(table_id);
+        let contents = match fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into())
+        };
+        let cells = read_entries(&contents, &path);
+
+        if !cells.is_empty() {
+            let n_cells = cells.len();
+
+            write_cells(db, table_id, cells).await?;
+            info!("Replayed {} journaled cells of table {}", n_cells, table_id);
+        }
+
+        fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    // Replays the journals of every table that is not resident. Holds the slot of each table while
+    // replaying its journal, so that the table is not loaded meanwhile.
+    pub(crate) async fn replay_all(&self, shared_tables: &SharedTablesMap, db: &Database) {
+        let mut entries = match fs::read_dir(&*self.dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("could not read journal directory {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let table_id = path.extension()
+                .filter(|&extension| extension == JOURNAL_EXTENSION)
+                .and_then(|_| path.file_stem()?.to_str()?.parse::<TableId>().ok());
+
+            let Some(table_id) = table_id else {
+                continue;
+            };
+            let slot = claim_slot(shared_tables, table_id).await;
+            let slot_guard = slot.lock().await;
+
+            if slot_guard.is_none() {
+                if let Err(e) = self.replay(table_id, db).await {
+                    error!("could not replay journal of table {}: {}", table_id, e);
+                }
+            }
+
+            drop(slot_guard);
+            drop(slot);
+            release_slot(shared_tables, table_id).await;
+        }
+    }
+
+    // === run_recovery ===========================================================================
+    //
+    // Replays leftover journals on startup and every time the database becomes available again.
+    // Resident tables are left to their write-behind task.
+    //
+    // ============================================================================================
+    pub(crate) async fn run_recovery(self, shared_tables: SharedTablesMap, db: Database) {
+        let mut status = db.status();
+
+        loop {
+            if *status.borrow_and_update() == DatabaseStatus::Available {
+                self.replay_all(&shared_tables, &db).await;
+            }
+            if status.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir).await?.sync_all().await
+}
+
+// Folds the entries of a journal into the latest text of every cell. A torn final line, left by a
+// crash during an append, belonged to an edit that was never acknowledged and is skipped.
+fn read_entries(contents: &str, path: &Path) -> HashMap<(usize, usize), String> {
+    let mut cells = HashMap::new();
+
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        match serde_json::from_str::<JournalEntry>(line) {
+            Ok(entry) => {
+                cells.insert(entry.cell, entry.text);
+            },
+            Err(e) => error!("skipping unreadable entry in {}: {}", path.display(), e)
+        }
+    }
+
+    cells
+}
+
+// === TableJournal ===============================================================================
+//
+// The open journal of a resident table.
+//
+// ================================================================================================
+pub(crate) struct TableJournal {
+    file: File,
+    path: PathBuf,
+    // The length of the entries known to be complete
+    len: u64
+}
+
+impl TableJournal {
+    // Appends an edit and waits until it has reached the disk. A failed append is cut off again,
+    // so that it cannot run into the next entry.
+    pub(crate) async fn append(&mut self, cell: (usize, usize), text: &str) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&JournalEntry { cell, text: String::from(text) })?;
+
+        line.push(b'\n');
+
+        let result = async {
+            self.file.write_all(&line).await?;
+            self.file.flush().await?;
+            self.file.sync_data().await
+        }.await;
+
+        match result {
+            Ok(()) => self.len += line.len() as u64,
+            Err(_) => {
+                self.file.set_len(self.len).await.ok();
+            }
+        }
+        result
+    }
+
+    // The length of the entries appended so far; marks the entries a flush covers
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Discards every entry, once the database holds all of them
+    pub(crate) async fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0).await?;
+        self.file.sync_data().await?;
+        self.len = 0;
+        Ok(())
+    }
+
+    // Discards the entries within the first `len` bytes, once the database holds all of them.
+    // Entries appended since are copied into a fresh journal, which then replaces this one.
+    pub(crate) async fn discard_through(&mut self, len: u64) -> io::Result<()> {
+        if len == self.len {
+            return self.truncate().await;
+        }
+
+        let mut tail = vec![];
+        let mut reader = File::open(&self.path).await?;
+
+        reader.seek(SeekFrom::Start(len)).await?;
+        reader.take(self.len - len).read_to_end(&mut tail).await?;
+
+        let tmp_path = self.path.with_extension(format!("{}.tmp", JOURNAL_EXTENSION));
+        let mut tmp = File::create(&tmp_path).await?;
+
+        tmp.write_all(&tail).await?;
+        tmp.sync_all().await?;
+        fs::rename(&tmp_path, &self.path).await?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path).await?;
+        self.len = tail.len() as u64;
+
+        match self.path.parent() {
+            Some(dir) => sync_dir(dir).await,
+            None => Ok(())
+        }
+    }
+
+    // Deletes the journal of an evicted table; it must have been truncated first
+    pub(crate) async fn remove(&self) {
+        if let Err(e) = fs::remove_file(&self.path).await {
+            error!("could not remove journal {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn journal_keeps_latest_text_until_truncated() {
+        let dir = std::env::temp_dir().join(format!("table-editor-journal-{}", std::process::id()));
+        let journal = Journal::new(&dir).await.unwrap();
+        let path = journal.path(7);
+        let mut table_journal = journal.open(7).await.unwrap();
+
+        table_journal.append((0, 1), "a").await.unwrap();
+        table_journal.append((2, 0), "line\nbreak").await.unwrap();
+        table_journal.append((0, 1), "ab").await.unwrap();
+
+        // A crash in the middle of an append leaves a partial line behind
+        let mut contents = fs::read_to_string(&path).await.unwrap();
+        contents.push_str("{\"cell\":[3,");
+
+        assert_eq!(read_entries(&contents, &path), HashMap::from([
+            ((0, 1), String::from("ab")),
+            ((2, 0), String::from("line\nbreak"))
+        ]));
+
+        table_journal.truncate().await.unwrap();
+        table_journal.append((1, 1), "c").await.unwrap();
+
+        let contents = fs::read_to_string(&path).await.unwrap();
+        assert_eq!(read_entries(&contents, &path), HashMap::from([((1, 1), String::from("c"))]));
+
+        // Entries appended while a flush was writing are kept once it completes
+        let flushed_len = table_journal.len();
+
+        table_journal.append((0, 0), "d").await.unwrap();
+        table_journal.discard_through(flushed_len).await.unwrap();
+        table_journal.append((0, 2), "e").await.unwrap();
+
+        let contents = fs::read_to_string(&path).await.unwrap();
+        assert_eq!(read_entries(&contents, &path), HashMap::from([
+            ((0, 0), String::from("d")),
+            ((0, 2), String::from("e"))
+        ]));
+
+        table_journal.remove().await;
+        fs::remove_dir(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant}
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::structure::{Axis, StructuralChange};
+
+// === LockGranularity ============================================================================
+//
+// The unit in which cells are locked.
+//
+// - cell: Editing or locking a cell locks only that cell
+// - row: Editing or locking a cell locks every cell in its row
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LockGranularity {
+    #[default]
+    Cell,
+    Row,
+}
+
+impl LockGranularity {
+    // Parses the value of tables.lock_granularity
+    pub(crate) fn from_db(granularity: &str) -> Option<Self> {
+        match granularity {
+            "cell" => Some(LockGranularity::Cell),
+            "row" => Some(LockGranularity::Row),
+            _ => None
+        }
+    }
+}
+
+// === LockPolicy =================================================================================
+//
+// How cells of a table are locked. Tables store their own settings in the `tables` table; unset
+// settings fall back to the server-wide defaults.
+//
+// - lock_duration_secs: How long a lock is held after the last edit, acquisition or renewal
+// - max_locks_per_client: How many lock units (cells or rows) a client may hold at once, if limited
+// - granularity: The unit in which cells are locked
+// - max_exclusive_secs: How long a client may hold an exclusive lock before it is released
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LockPolicy {
+    pub(crate) lock_duration_secs: u32,
+    pub(crate) max_locks_per_client: Option<u32>,
+    pub(crate) granularity: LockGranularity,
+    pub(crate) max_exclusive_secs: u32
+}
+
+impl Default for LockPolicy {
+    fn default() -> Self {
+        Self {
+            lock_duration_secs: 3,
+            max_locks_per_client: None,
+            granularity: LockGranularity::Cell,
+            max_exclusive_secs: 300
+        }
+    }
+}
+
+impl LockPolicy {
+    // The cells locked together with the cell at (r, c) in a table `n_cols` wide
+    pub(crate) fn lock_scope(&self, (r, c): (usize, usize), n_cols: usize) -> Vec<(usize, usize)> {
+        match self.granularity {
+            LockGranularity::Cell => vec![(r, c)],
+            LockGranularity::Row => (0..n_cols).map(|i_col| (r, i_col)).collect()
+        }
+    }
+
+    // When a lock acquired or renewed now expires
+    pub(crate) fn lease_deadline(&self) -> Instant {
+        Instant::now() + Duration::from_secs(self.lock_duration_secs.into())
+    }
+}
+
+// Identifies a range lock within its table
+pub(crate) type RangeLockId = u64;
+
+// === RangeLockData ==============================================================================
+//
+// A rectangle of cells locked as a unit with LockRange. Each locked cell refers to its range
+// through CellLockData::range_id; the lease is tracked here rather than per cell, so the cells
+// are renewed and released together.
+//
+// - owner_id: The client id of the owner
+// - expires_at: When the range is released unless renewed
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RangeLockData {
+    pub(crate) owner_id: u64,
+    pub(crate) expires_at: Instant
+}
+
+// How long a locked cell stays locked: until its own deadline, or for as long as its range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lease {
+    Until(Instant),
+    Range(RangeLockId),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ScheduledLock {
+    owner_id: u64,
+    lease: Lease
+}
+
+// What a deadline in the LockSchedule's queue belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Expiry {
+    Cell((usize, usize)),
+    Range(RangeLockId),
+}
+
+// === LockSchedule ===============================================================================
+//
+// Indexes the locks held on a table by cell, along with when they expire, so that expiring,
+// counting and releasing locks only ever visits cells that actually hold one. Every change to a
+// cell's CellLockData is mirrored here.
+//
+// Deadlines are kept in order, so finding the earliest one costs O(log n). Renewing or releasing
+// a lock leaves its old deadline in the queue; such stale entries no longer match the lock's
+// lease and are discarded once they reach the front.
+//
+// The table's lock manager sleeps until the earliest deadline, and is only woken when a lock is
+// scheduled to expire before it.
+//
+// ================================================================================================
+pub(crate) struct LockSchedule {
+    cells: HashMap<(usize, usize), ScheduledLock>,
+    ranges: HashMap<RangeLockId, RangeLockData>,
+    deadlines: BTreeSet<(Instant, Expiry)>,
+    wake: Arc<Notify>
+}
+
+impl LockSchedule {
+    pub(crate) fn new(wake: Arc<Notify>) -> Self {
+        Self { cells: HashMap::new(), ranges: HashMap::new(), deadlines: BTreeSet::new(), wake }
+    }
+
+    // Queues a deadline, waking the lock manager if it is now the earliest
+    fn push_deadline(&mut self, deadline: Instant, expiry: Expiry) {
+        let is_earliest = self.deadlines.first().is_none_or(|&(earliest, _)| deadline < earliest);
+
+        self.deadlines.insert((deadline, expiry));
+        if is_earliest {
+            self.wake();
+        }
+    }
+
+    // Whether a queued deadline still belongs to a held lock
+    fn is_current(&self, deadline: Instant, expiry: Expiry) -> bool {
+        match expiry {
+            Expiry::Cell(pos) => self.cells.get(&pos).is_some_and(|lock| lock.lease == Lease::Until(deadline)),
+            Expiry::Range(range_id) => self.ranges.get(&range_id).is_some_and(|range| range.expires_at == deadline)
+        }
+    }
+
+    // Wakes the lock manager so it picks up a new deadline
+    pub(crate) fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub(crate) fn schedule_cell(&mut self, pos: (usize, usize), owner_id: u64, lease: Lease) {
+        self.cells.insert(pos, ScheduledLock { owner_id, lease });
+        if let Lease::Until(deadline) = lease {
+            self.push_deadline(deadline, Expiry::Cell(pos));
+        }
+    }
+
+    pub(crate) fn unschedule_cell(&mut self, pos: (usize, usize)) {
+        self.cells.remove(&pos);
+    }
+
+    pub(crate) fn schedule_range(&mut self, range_id: RangeLockId, range: RangeLockData) {
+        self.ranges.insert(range_id, range);
+        self.push_deadline(range.expires_at, Expiry::Range(range_id));
+    }
+
+    pub(crate) fn renew_range(&mut self, range_id: RangeLockId, expires_at: Instant) {
+        if let Some(range) = self.ranges.get_mut(&range_id) {
+            range.expires_at = expires_at;
+            self.push_deadline(expires_at, Expiry::Range(range_id));
+        }
+    }
+
+    // Forgets the given ranges, returning the cells that belong to them
+    pub(crate) fn remove_ranges(&mut self, range_ids: &HashSet<RangeLockId>) -> Vec<(usize, usize)> {
+        self.ranges.retain(|range_id, _| !range_ids.contains(range_id));
+        self.cells.iter()
+            .filter(|(_, lock)| matches!(lock.lease, Lease::Range(range_id) if range_ids.contains(&range_id)))
+            .map(|(pos, _)| *pos)
+            .collect()
+    }
+
+    pub(crate) fn locked_cells(&self) -> Vec<(usize, usize)> {
+        self.cells.keys().copied().collect()
+    }
+
+    // The cells locked by the client, with their leases
+    pub(crate) fn held_by(&self, client_id: u64) -> impl Iterator<Item = ((usize, usize), Lease)> + '_ {
+        self.cells.iter()
+            .filter(move |(_, lock)| lock.owner_id == client_id)
+            .map(|(pos, lock)| (*pos, lock.lease))
+    }
+
+    // A cell within the given rows that is locked by another client, with its holder
+    pub(crate) fn held_by_other(&self, client_id: u64, rows: Range<usize>) -> Option<((usize, usize), u64)> {
+        self.cells.iter()
+            .find(|((i_row, _), lock)| rows.contains(i_row) && lock.owner_id != client_id)
+            .map(|(pos, lock)| (*pos, lock.owner_id))
+    }
+
+    pub(crate) fn ranges_held_by(&self, client_id: u64) -> HashSet<RangeLockId> {
+        self.ranges.iter()
+            .filter(|(_, range)| range.owner_id == client_id)
+            .map(|(range_id, _)| *range_id)
+            .collect()
+    }
+
+    // The earliest deadline of any held lock. Discards the stale deadlines in front of it.
+    pub(crate) fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(&(deadline, expiry)) = self.deadlines.first() {
+            if self.is_current(deadline, expiry) {
+                return Some(deadline);
+            }
+            self.deadlines.pop_first();
+        }
+
+        None
+    }
+
+    // Takes the cell locks and range locks whose deadline has passed off the queue; the caller
+    // must release them
+    pub(crate) fn expired(&mut self, now: Instant) -> (Vec<(usize, usize)>, HashSet<RangeLockId>) {
+        let mut cells = vec![];
+        let mut ranges = HashSet::new();
+
+        while let Some(&(deadline, expiry)) = self.deadlines.first().filter(|&&(deadline, _)| deadline <= now) {
+            self.deadlines.pop_first();
+
+            if !self.is_current(deadline, expiry) {
+                continue;
+            }
+            match expiry {
+                Expiry::Cell(pos) => cells.push(pos),
+                Expiry::Range(range_id) => {
+                    ranges.insert(range_id);
+                }
+            }
+        }
+
+        (cells, ranges)
+    }
+
+    // Makes the schedule follow its cells through a structural change. Locks on deleted cells are
+    // dropped along with the cells.
+    pub(crate) fn apply_change(&mut self, change: &StructuralChange) {
+        self.cells = self.cells.drain()
+            .filter_map(|((r, c), lock)| {
+                let pos = match change.axis() {
+                    Axis::Rows => (change.map_index(r)?, c),
+                    Axis::Cols => (r, change.map_index(c)?)
+                };
+
+                Some((pos, lock))
+            })
+            .collect();
+
+        // Cell deadlines are queued by position, so they are queued afresh
+        self.deadlines.retain(|(_, expiry)| matches!(expiry, Expiry::Range(_)));
+        for (pos, lock) in &self.cells {
+            if let Lease::Until(deadline) = lock.lease {
+                self.deadlines.insert((deadline, Expiry::Cell(*pos)));
+            }
+        }
+    }
+}
+
+// === ExclusiveScope =============================================================================
+//
+// The part of a table an exclusive lock covers.
+//
+// - table: The whole table, including its structure
+// - row: A single row
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum ExclusiveScope {
+    Table,
+    Row { row: usize },
+}
+
+// === ExclusiveLock ==============================================================================
+//
+// Gives a single client sole editing rights over its scope until it is released, its holder
+// disconnects, or it reaches the table's maximum hold time. While held, all other clients'
+// mutations within the scope are rejected, as are their structural changes.
+//
+// - owner_id: The client id of the holder
+// - scope: The part of the table covered
+// - expires_at: When the lock is released regardless of the holder
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ExclusiveLock {
+    pub(crate) owner_id: u64,
+    pub(crate) scope: ExclusiveScope,
+    pub(crate) expires_at: Instant
+}
+
+// How an exclusive lock is described to clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExclusiveLockView {
+    pub(crate) client_id: u64,
+    pub(crate) scope: ExclusiveScope,
+    pub(crate) expires_in_secs: u64
+}
+
+impl ExclusiveLock {
+    pub(crate) fn new(owner_id: u64, scope: ExclusiveScope, max_hold_secs: u32) -> Self {
+        Self { owner_id, scope, expires_at: Instant::now() + Duration::from_secs(max_hold_secs.into()) }
+    }
+
+    // Whether the lock covers any of the given rows; None stands for the structure of the table
+    pub(crate) fn covers(&self, rows: Option<(usize, usize)>) -> bool {
+        match (self.scope, rows) {
+            (ExclusiveScope::Table, _) | (ExclusiveScope::Row { .. }, None) => true,
+            (ExclusiveScope::Row { row }, Some((top, bottom))) => (top..=bottom).contains(&row)
+        }
+    }
+
+    // Whether two scopes cannot be held by different clients at once
+    pub(crate) fn overlaps(&self, scope: ExclusiveScope) -> bool {
+        match scope {
+            ExclusiveScope::Table => true,
+            ExclusiveScope::Row { row } => self.covers(Some((row, row)))
+        }
+    }
+
+    pub(crate) fn view(&self) -> ExclusiveLockView {
+        ExclusiveLockView {
+            client_id: self.owner_id,
+            scope: self.scope,
+            expires_in_secs: self.expires_at.saturating_duration_since(Instant::now()).as_secs()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive_scopes_cover_rows_and_structure() {
+        let table = ExclusiveLock::new(1, ExclusiveScope::Table, 60);
+        let row = ExclusiveLock::new(1, ExclusiveScope::Row { row: 3 }, 60);
+
+        assert!(table.covers(Some((0, 0))) && table.covers(None));
+        assert!(row.covers(Some((2, 4))) && row.covers(None));
+        assert!(!row.covers(Some((4, 9))));
+
+        assert!(row.overlaps(ExclusiveScope::Table) && row.overlaps(ExclusiveScope::Row { row: 3 }));
+        assert!(!row.overlaps(ExclusiveScope::Row { row: 2 }));
+        assert!(table.overlaps(ExclusiveScope::Row { row: 2 }));
+    }
+
+    #[test]
+    fn row_granularity_locks_whole_rows() {
+        let policy = LockPolicy { granularity: LockGranularity::Row, ..LockPolicy::default() };
+
+        assert_eq!(policy.lock_scope((1, 2), 3), vec![(1, 0), (1, 1), (1, 2)]);
+        assert_eq!(LockPolicy::default().lock_scope((1, 2), 3), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn schedule_tracks_deadlines_and_follows_cells() {
+        let now = Instant::now();
+        let mut schedule = LockSchedule::new(Arc::new(Notify::new()));
+
+        schedule.schedule_cell((0, 0), 1, Lease::Until(now));
+        schedule.schedule_cell((2, 1), 1, Lease::Range(5));
+        schedule.schedule_cell((3, 1), 2, Lease::Until(now + Duration::from_secs(10)));
+        schedule.schedule_range(5, RangeLockData { owner_id: 1, expires_at: now + Duration::from_secs(5) });
+
+        assert_eq!(schedule.next_deadline(), Some(now));
+        assert_eq!(schedule.expired(now), (vec![(0, 0)], HashSet::new()));
+        assert_eq!(schedule.next_deadline(), Some(now + Duration::from_secs(5)));
+
+        // A renewed range is only due at its new deadline
+        schedule.renew_range(5, now + Duration::from_secs(7));
+        assert_eq!(schedule.expired(now + Duration::from_secs(5)), (vec![], HashSet::new()));
+        assert_eq!(schedule.next_deadline(), Some(now + Duration::from_secs(7)));
+        assert_eq!(schedule.expired(now + Duration::from_secs(7)).1, HashSet::from([5]));
+
+        schedule.apply_change(&StructuralChange::Delete { axis: Axis::Rows, start: 0, count: 1 });
+
+        let mut held: Vec<_> = schedule.held_by(1).collect();
+
+        held.sort_by_key(|(pos, _)| *pos);
+        assert_eq!(held, vec![((1, 1), Lease::Range(5))]);
+        assert_eq!(schedule.held_by_other(1, 0..2), None);
+        assert_eq!(schedule.held_by_other(1, 0..3), Some(((2, 1), 2)));
+        assert_eq!(schedule.held_by_other(2, 0..3), Some(((1, 1), 1)));
+        assert_eq!(schedule.remove_ranges(&HashSet::from([5])), vec![(1, 1)]);
+        assert_eq!(schedule.next_deadline(), Some(now + Duration::from_secs(10)));
+    }
+}
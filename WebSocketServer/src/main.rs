@@ -1,11 +1,9 @@
 use std::{
-    net::SocketAddr,
     sync::{
         Arc,
     },
-    thread,
-    time::Duration,
-    collections::HashMap,
+    time::{Duration, Instant},
+    collections::{HashMap, VecDeque},
     env,
     error::Error,
     fmt
@@ -14,34 +12,80 @@ use std::{
 use futures::{
     SinkExt,
     StreamExt,
-    lock::Mutex,
-    executor::block_on
+    lock::Mutex
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast;
+use tokio::{
+    net::TcpListener,
+    sync::{Notify, broadcast, mpsc, watch}
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use warp::ws::{Message, WebSocket};
-use warp::Filter;
+use warp::{Filter, Reply};
+use warp::http::StatusCode;
 use tokio_postgres as postgres;
+use log::{error, info, warn};
+
+mod access;
+mod auth;
+mod config;
+mod database;
+mod journal;
+mod locking;
+mod offsets;
+mod operations;
+mod persistence;
+mod protection;
+mod residency;
+mod shutdown;
+mod structure;
+mod tls;
+mod validation;
+
+use access::{ACCESS_CHECK_INTERVAL, Role, table_role};
+use auth::{AuthenticatedUser, BEARER_PROTOCOL, JwtVerifier, token_from_protocol_header};
+use config::Config;
+use database::{Database, DatabaseStatus};
+use journal::{Journal, TableJournal};
+use locking::{ExclusiveLock, ExclusiveLockView, ExclusiveScope, LockGranularity, LockPolicy, LockSchedule, RangeLockId};
+use offsets::{OffsetUnit, localize_message};
+use operations::{ClientSession, expire_locks, handle_client_message, release_client_locks};
+use persistence::{DirtyCells, WriteBehindPolicy, run_write_behind};
+use protection::{ProtectedRange, fetch_protected_ranges};
+use residency::{ResidencyPolicy, claim_slot, enforce_resident_cap, release_slot, schedule_eviction};
+use shutdown::{ShutdownPolicy, drain, shutdown_signal};
+use tls::{accept_tls, load_listener};
 
 // === CellLockData ===============================================================================
 //
 // Contains information on the current owner of a table cell.
 //
 // - owner_id: The client id of the owner
-// - duration_secs: How many seconds are left before the client relinquishes ownership of the cell
-// (should be refreshed whenever the client performs an operation on the cell)
+// - range_id: The range lock the cell belongs to, if any
+//
+// When the lock expires is tracked by the table's LockSchedule; it is pushed back whenever the
+// client performs an operation on the cell.
 //
 // ================================================================================================
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 struct CellLockData {
     owner_id: u64,
-    duration_secs: u32
+    range_id: Option<RangeLockId>
 }
 
+// === TableCell ==================================================================================
+//
+// - text: The current text of the cell
+// - lock: The current lock on the cell, if any
+// - waiters: Clients queued for the lock, in order; the first is granted the lock once it is
+// released
+//
+// ================================================================================================
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct TableCell {
     text: String,
-    lock: Option<CellLockData>
+    lock: Option<CellLockData>,
+    waiters: VecDeque<u64>
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -58,14 +102,88 @@ struct TableCellClientView {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ServerSocketMessage {
-    Init { client_id: u64, table: Vec<Vec<TableCellClientView>> },
-    Insert { client_id: u64, cell: (usize, usize), index: usize, text: String },
-    Delete { client_id: u64, cell: (usize, usize), start: usize, end: usize },
-    Replace { client_id: u64, cell: (usize, usize), start: usize, end: usize, text: String },
+    Init {
+        client_id: u64,
+        role: Role,
+        offset_unit: OffsetUnit,
+        lock_policy: LockPolicy,
+        table: Vec<Vec<TableCellClientView>>,
+        protected_ranges: Vec<ProtectedRange>,
+        exclusive_locks: Vec<ExclusiveLockView>,
+        database_status: DatabaseStatus
+    },
+    // Text offsets are broadcast as byte offsets into `text_before`, the cell text prior to the
+    // edit, and converted into each client's offset unit just before sending.
+    Insert {
+        client_id: u64, cell: (usize, usize), index: usize, text: String,
+        #[serde(skip)] text_before: Arc<str>
+    },
+    Delete {
+        client_id: u64, cell: (usize, usize), start: usize, end: usize,
+        #[serde(skip)] text_before: Arc<str>
+    },
+    Replace {
+        client_id: u64, cell: (usize, usize), start: usize, end: usize, text: String,
+        #[serde(skip)] text_before: Arc<str>
+    },
     InsertRows { client_id: u64, insertion_index: usize, num_rows: usize },
     InsertCols { client_id: u64, insertion_index: usize, num_cols: usize },
+    DeleteRows { client_id: u64, start: usize, count: usize },
+    DeleteCols { client_id: u64, start: usize, count: usize },
+    MoveRows { client_id: u64, from: usize, count: usize, to: usize },
+    MoveCols { client_id: u64, from: usize, count: usize, to: usize },
+    // The protected ranges of the table, sent after every structural change
+    ProtectedRanges { protected_ranges: Vec<ProtectedRange> },
     AcquireLock { client_id: u64, cell: (usize, usize) },
+    // The lock on `cell` was handed to the first client queued for it
+    LockGranted { client_id: u64, cell: (usize, usize) },
+    // `cell` came free, but the first client queued for it may not take it (e.g. it has reached
+    // its lock limit) and has left the queue
+    LockDenied { client_id: u64, cell: (usize, usize), code: ErrorCode },
+    // Every cell in the rectangle [top_left, bottom_right] has been locked by the client
+    LockRange { client_id: u64, range_id: RangeLockId, top_left: (usize, usize), bottom_right: (usize, usize) },
+    // A client has taken sole editing rights over `scope`
+    ExclusiveAcquired { client_id: u64, scope: ExclusiveScope, expires_in_secs: u64 },
+    // An exclusive lock was released by its holder, expired, or was broken by a table owner
+    ExclusiveReleased { client_id: u64, scope: ExclusiveScope, broken_by: Option<u64> },
+    // The client's last connection to the table closed; its locks have been released
+    ClientLeft { client_id: u64 },
+    // The server is shutting down and is about to close the connection
+    ServerShutdown { reconnect_after_ms: u64 },
+    // The server lost or regained its connection to the database. While it is unavailable, edits
+    // are kept in memory but structural changes fail with storage_error.
+    DatabaseStatus { status: DatabaseStatus },
     ReleaseLock { cell: (usize, usize) },
+    // Sent only to the client that made the request
+    Ack { request_id: u64 },
+    Error { request_id: Option<u64>, code: ErrorCode, message: String },
+}
+
+// === ErrorCode ==================================================================================
+//
+// Stable, machine-readable reasons for rejecting a client message.
+//
+// - lock_conflict: The target cell is locked by another client
+// - out_of_range: A cell coordinate or row/column index falls outside the table
+// - invalid_range: A text range does not describe a valid substring of the cell text
+// - invalid_operation: The operation is well-formed but cannot be applied (e.g. deleting every row)
+// - lock_limit_exceeded: The client already holds as many locks as the table allows
+// - forbidden: The client is not permitted to perform the operation or to access the table
+// - parse_error: The message could not be parsed
+// - storage_error: The change could not be saved to the database and was not applied
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    LockConflict,
+    OutOfRange,
+    InvalidRange,
+    InvalidOperation,
+    LockLimitExceeded,
+    Forbidden,
+    ParseError,
+    StorageError,
 }
 
 // === ClientSocketMessage ========================================================================
@@ -80,7 +198,67 @@ enum ClientSocketMessage {
     Delete { cell: (usize, usize), start: usize, end: usize },
     Replace { cell: (usize, usize), start: usize, end: usize, text: String },
     InsertRows { insertion_index: usize, num_rows: usize },
-    InsertCols { insertion_index: usize, num_cols: usize }
+    InsertCols { insertion_index: usize, num_cols: usize },
+    DeleteRows { start: usize, count: usize },
+    DeleteCols { start: usize, count: usize },
+    // Moves the block of rows (or columns) [from, from + count) so that its first row ends up at
+    // index `to` of the resulting table.
+    MoveRows { from: usize, count: usize, to: usize },
+    MoveCols { from: usize, count: usize, to: usize },
+    // Explicit cell locks, e.g. taken when a cell gains focus and released when it loses focus.
+    // A held lock expires unless renewed.
+    AcquireLock { cell: (usize, usize) },
+    ReleaseLock { cell: (usize, usize) },
+    RenewLock { cell: (usize, usize) },
+    // Atomically locks every cell in the rectangle [top_left, bottom_right], or none of them.
+    // Releasing or renewing any cell of the range releases or renews the whole range.
+    LockRange { top_left: (usize, usize), bottom_right: (usize, usize) },
+    // Takes sole editing rights over the whole table or a single row. Table owners may set
+    // `force` to break conflicting exclusive locks held by other clients.
+    AcquireExclusive {
+        scope: ExclusiveScope,
+        #[serde(default)]
+        force: bool
+    },
+    ReleaseExclusive { scope: ExclusiveScope },
+    // Acquires the lock on the cell if it is free, and otherwise queues for it; the lock is granted
+    // (with LockGranted) once released by its holder, covering the same cells and counting towards
+    // the same limit as AcquireLock, or denied (with LockDenied) if the client may no longer take
+    // it. ReleaseLock leaves the queue.
+    QueueLock { cell: (usize, usize) },
+    // Forcibly releases the lock on the cell (table owners only)
+    BreakLock { cell: (usize, usize) }
+}
+
+// === ClientRequest ==============================================================================
+//
+// Wraps a client message with an optional request id. When present, the server answers the
+// request with either an Ack or an Error carrying the same id.
+//
+// ================================================================================================
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClientRequest {
+    #[serde(default)]
+    request_id: Option<u64>,
+    #[serde(flatten)]
+    message: ClientSocketMessage
+}
+
+// === ConnectParams ==============================================================================
+//
+// Query parameters accepted on the /ws/{table_id} upgrade request.
+//
+// - offset_unit: The unit in which the client expresses text offsets (defaults to byte)
+// - token: The JWT issued by the REST API; may instead be passed in the Sec-WebSocket-Protocol
+//   header
+//
+// ================================================================================================
+#[derive(Debug, Clone, Deserialize)]
+struct ConnectParams {
+    #[serde(default)]
+    offset_unit: OffsetUnit,
+    #[serde(default)]
+    token: Option<String>
 }
 
 type SharedTableCells = Vec<Vec<Arc<Mutex<TableCell>>>>;
@@ -88,13 +266,67 @@ struct SharedTable {
     n_rows: usize,
     n_cols: usize,
     cells: SharedTableCells,
+    protected_ranges: Vec<ProtectedRange>,
+    lock_policy: LockPolicy,
+    // Leaf lock: may be taken while holding cell locks
+    locks: Mutex<LockSchedule>,
+    // Held for the whole of a flush, including its database write, so that flushes never overlap
+    // and the structure of the table cannot change under one. Taken after the table lock, if at
+    // all, and before the leaf locks.
+    flush: Arc<Mutex<()>>,
+    // Leaf lock: cells edited since they were last written to the database
+    dirty: Arc<Mutex<DirtyCells>>,
+    // Leaf lock: edits not yet confirmed by the database, appended before they are acknowledged
+    journal: Arc<Mutex<TableJournal>>,
+    next_range_id: RangeLockId,
+    exclusive_locks: Vec<ExclusiveLock>,
     client_count: u32,
+    // Open connections per client. Clients are identified by user id, so a user with the table
+    // open in several tabs keeps their locks until the last of them disconnects.
+    client_sessions: HashMap<u64, u32>,
+    // When the last client disconnected; None while any client is connected
+    idle_since: Option<Instant>,
+    // Cancelled when the table is evicted, stopping its background tasks
+    lifecycle: CancellationToken,
     sender: broadcast::Sender<ServerSocketMessage>
 }
 type SharedTableRef = Arc<Mutex<SharedTable>>;
-type SharedTablesMap = Arc<Mutex<HashMap<TableId, SharedTableRef>>>;
+// A table's place in the map; None until the table is loaded. Whoever loads, evicts or replays the
+// table holds its slot meanwhile, so the map itself is never locked across database or disk I/O.
+// Taken after the map, if at all, and before the table.
+type TableSlot = Arc<Mutex<Option<SharedTableRef>>>;
+type SharedTablesMap = Arc<Mutex<HashMap<TableId, TableSlot>>>;
 type TableId = i64;// corresponds to Postgres BIGINT
-type SharedClientId = Arc<Mutex<u64>>;
+
+// === ServerContext ==============================================================================
+//
+// State shared by every connection to the server.
+//
+// - shared_tables: The tables currently held in memory
+// - db: The database connection pool
+// - residency: How long tables are kept in memory once they have no clients
+// - write_behind: How edited cells are written back to the database
+// - broadcast_capacity: How many messages each table buffers for clients that fall behind
+// - lock_defaults: The lock settings of tables that do not set their own
+// - journal: Where edits are journaled until the database has them
+// - shutdown_policy: How long clients wait to reconnect once the server shuts down
+// - shutdown: Cancelled once the server starts shutting down, closing every connection
+// - connections: Tracks open connections, so shutdown can wait for them to close
+//
+// ================================================================================================
+#[derive(Clone)]
+struct ServerContext {
+    shared_tables: SharedTablesMap,
+    db: Database,
+    residency: ResidencyPolicy,
+    write_behind: WriteBehindPolicy,
+    broadcast_capacity: usize,
+    lock_defaults: LockPolicy,
+    journal: Journal,
+    shutdown_policy: ShutdownPolicy,
+    shutdown: CancellationToken,
+    connections: TaskTracker
+}
 
 #[derive(Debug, Clone, Copy)]
 struct NoTableError {
@@ -103,7 +335,7 @@ struct NoTableError {
 
 impl NoTableError {
     fn new(table_id: TableId) -> Self {
-        Self { table_id: table_id }
+        Self { table_id }
     }
 }
 
@@ -115,17 +347,49 @@ impl fmt::Display for NoTableError {
 
 impl Error for NoTableError {}
 
-async fn fetch_table(db_cli: &postgres::Client, table_id: TableId) -> Result<(SharedTableCells, usize, usize), NoTableError> {
-    let rows = match db_cli.query("SELECT width, height FROM tables WHERE id = $1", &[&table_id]).await {
+// Everything fetch_table loads for a table
+struct FetchedTable {
+    cells: SharedTableCells,
+    protected_ranges: Vec<ProtectedRange>,
+    lock_policy: LockPolicy,
+    n_rows: usize,
+    n_cols: usize
+}
+
+async fn fetch_table(db_cli: &postgres::Client, table_id: TableId, default_policy: LockPolicy) -> Result<FetchedTable, NoTableError> {
+    let rows = match db_cli.query(
+        "SELECT width, height, lock_duration_secs, max_locks_per_client, lock_granularity, max_exclusive_secs FROM tables WHERE id = $1",
+        &[&table_id]
+    ).await {
         Err(_) => { return Err(NoTableError::new(table_id)); },
         Ok(rows) => rows
     };
 
-    let (width, height) = if let Some(row) = rows.first() {
+    let (width, height, lock_policy) = if let Some(row) = rows.first() {
         let width : i32 = row.get(0);
         let height : i32 = row.get(1);
+        let lock_duration_secs : Option<i32> = row.get(2);
+        let max_locks_per_client : Option<i32> = row.get(3);
+        let lock_granularity : Option<&str> = row.get(4);
+        let max_exclusive_secs : Option<i32> = row.get(5);
+
+        // Unset (or invalid) settings fall back to the server-wide defaults
+        let lock_policy = LockPolicy {
+            lock_duration_secs: lock_duration_secs
+                .and_then(|secs| u32::try_from(secs).ok())
+                .unwrap_or(default_policy.lock_duration_secs),
+            max_locks_per_client: max_locks_per_client
+                .and_then(|max| u32::try_from(max).ok())
+                .or(default_policy.max_locks_per_client),
+            granularity: lock_granularity
+                .and_then(LockGranularity::from_db)
+                .unwrap_or(default_policy.granularity),
+            max_exclusive_secs: max_exclusive_secs
+                .and_then(|secs| u32::try_from(secs).ok())
+                .unwrap_or(default_policy.max_exclusive_secs)
+        };
 
-        (width, height)
+        (width, height, lock_policy)
     } else {
         return Err(NoTableError::new(table_id))
     };
@@ -139,7 +403,7 @@ async fn fetch_table(db_cli: &postgres::Client, table_id: TableId) -> Result<(Sh
         Ok(rows) => rows
     };
 
-    let mut table_data = vec![ vec![ String::new(); width as usize ]; height as usize ];
+    let mut table_data = vec![ vec![ String::new(); width ]; height ];
 
     for row in rows {
         let i_row : i32 = row.get(0);
@@ -148,28 +412,28 @@ async fn fetch_table(db_cli: &postgres::Client, table_id: TableId) -> Result<(Sh
 
         match (TryInto::<usize>::try_into(i_row), TryInto::<usize>::try_into(i_col)) {
             (Err(e_row), Ok(_)) => {
-                eprintln!("ERROR: could not convert {} into usize row index: {}", i_row, e_row);
+                error!("could not convert {} into usize row index: {}", i_row, e_row);
             },
             (Ok(_), Err(e_col)) => {
-                eprintln!("ERROR: could not convert {} into usize col index: {}", i_col, e_col);
+                error!("could not convert {} into usize col index: {}", i_col, e_col);
             },
             (Err(e_row), Err(e_col)) => {
-                eprintln!(
-                    "ERROR: could not convert {}, {} into usize indices: {}, {}",
+                error!(
+                    "could not convert {}, {} into usize indices: {}, {}",
                     i_row, i_col, e_row, e_col
                 );
             },
             (Ok(i_row), Ok(i_col)) => match table_data.get_mut(i_row) {
                 None => {
-                    eprintln!(
-                        "ERROR: row index {} falls outside table of dimension {}x{}",
+                    error!(
+                        "row index {} falls outside table of dimension {}x{}",
                         i_row, height, width
                     );
                 },
                 Some(row) => match row.get_mut(i_col) {
                     None => {
-                        eprintln!(
-                            "ERROR: col index {} falls outside table of dimension {}x{}",
+                        error!(
+                            "col index {} falls outside table of dimension {}x{}",
                             i_col, height, width
                         );
                     },
@@ -185,11 +449,17 @@ async fn fetch_table(db_cli: &postgres::Client, table_id: TableId) -> Result<(Sh
         .map(|i_row| {
             (0..width).map(|i_col| Arc::new(Mutex::new(TableCell {
                 text: table_data[i_row][i_col].clone(),
-                lock: None
+                lock: None,
+                waiters: VecDeque::new()
             }))).collect()
         }).collect();
 
-    Ok((table, height, width))
+    let protected_ranges = match fetch_protected_ranges(db_cli, table_id).await {
+        Err(_) => { return Err(NoTableError::new(table_id)); },
+        Ok(ranges) => ranges
+    };
+
+    Ok(FetchedTable { cells: table, protected_ranges, lock_policy, n_rows: height, n_cols: width })
 }
 
 // === Pseudocode =================================================================================
@@ -198,76 +468,207 @@ async fn fetch_table(db_cli: &postgres::Client, table_id: TableId) -> Result<(Sh
 //  - MutexLockedCells[][]
 //  - client_count
 //  - broadcast_channel
-//  - lock_manager_thread_handle
+//  - lock_manager_task
+//  - idle_since (evicted once idle for the grace period)
 // TableMap: TableId => Table
 //
 // ================================================================================================
 
 #[tokio::main]
 async fn main() {
-    // All services serve on port 3000 by default
-    let port = 3000u16;
-    let shared_tables = Arc::new(Mutex::new(HashMap::<TableId, SharedTableRef>::new()));
-    let next_client_id: SharedClientId = Arc::new(Mutex::new(0u64));
-
-    // Configure database client
-    let (db_user, db_dbname, db_pass) = match (env::var("POSTGRES_USER"), env::var("POSTGRES_DB"), env::var("POSTGRES_PASSWORD")) {
-        (Ok(user), Ok(dbname), Ok(pass)) => (user, dbname, pass),
-        (e_user, e_dbname, e_pass) => {
-            if let Err(e) = e_user {
-                eprintln!("Could not get POSTGRES_USER: {}", e);
-            }
-            if let Err(e) = e_dbname {
-                eprintln!("Could not get POSTGRES_DB: {}", e);
-            }
-            if let Err(e) = e_pass {
-                eprintln!("Could not get POSTGRES_PASSWORD: {}", e);
-            }
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            // The logger is not set up yet
+            eprintln!("ERROR: {}", e);
             return;
         }
     };
-    let conn_str = format!("host=database user={} dbname={} password={}", db_user, db_dbname, db_pass);
-    let (db_cli, db_conn) = match postgres::connect(conn_str.as_str(), postgres::NoTls).await {
-        Ok((cli, conn)) => (Arc::new(Mutex::new(cli)), conn),
+
+    env_logger::Builder::new().filter_level(config.log_level).init();
+
+    // Load the certificate of the wss:// listener, if the server terminates TLS itself
+    let tls_cert = match config.tls.clone().map(load_listener).transpose() {
+        Ok(tls_cert) => tls_cert,
         Err(e) => {
-            eprintln!("ERROR: could not connect to database -- {}", e);
+            error!("could not load TLS certificate -- {}", e);
             return;
         }
     };
 
-    tokio::spawn(async move {
-        if let Err(e) = db_conn.await {
-            eprintln!("database connection error: {}", e);
-        };
-    });
+    let shared_tables = Arc::new(Mutex::new(HashMap::<TableId, TableSlot>::new()));
 
-    let shared_tables_filter = warp::any().map({
-        let shared_tables = Arc::clone(&shared_tables);
-        move || Arc::clone(&shared_tables)
-    });
+    // Configure the database connection pool
+    let db = match Database::new(&config.database) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("could not configure database connection pool -- {}", e);
+            return;
+        }
+    };
 
-    let db_cli_filter = warp::any().map({
-        let db_cli = Arc::clone(&db_cli);
-        move || Arc::clone(&db_cli)
-    });
+    tokio::spawn(db.clone().run_health_monitor(config.database.health_check_interval));
+
+    // Edits must be journaled before they are acknowledged, so the server cannot run without one
+    let journal = match Journal::new(&config.journal_dir).await {
+        Ok(journal) => journal,
+        Err(e) => {
+            error!("could not create journal directory -- {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(journal.clone().run_recovery(Arc::clone(&shared_tables), db.clone()));
+
+    // Configure verification of the JWTs issued by the REST API
+    let jwt_verifier = match env::var("JWT_SECRET") {
+        Ok(secret) => match JwtVerifier::from_base64_secret(&secret) {
+            Ok(verifier) => Arc::new(verifier),
+            Err(e) => {
+                error!("JWT_SECRET is not a valid base64-encoded key -- {}", e);
+                return;
+            }
+        },
+        Err(e) => {
+            error!("could not get JWT_SECRET: {}", e);
+            return;
+        }
+    };
 
-    let next_client_id_filter = warp::any().map({
-        let next_client_id = Arc::clone(&next_client_id);
-        move || Arc::clone(&next_client_id)
+    let shutdown = CancellationToken::new();
+    let connections = TaskTracker::new();
+    let server = ServerContext {
+        shared_tables: Arc::clone(&shared_tables),
+        db: db.clone(),
+        residency: config.residency,
+        write_behind: config.write_behind,
+        broadcast_capacity: config.broadcast_capacity,
+        lock_defaults: config.lock_defaults,
+        journal,
+        shutdown_policy: config.shutdown,
+        shutdown: shutdown.clone(),
+        connections: connections.clone()
+    };
+    let server_filter = warp::any().map(move || server.clone());
+
+    let jwt_verifier_filter = warp::any().map({
+        let jwt_verifier = Arc::clone(&jwt_verifier);
+        move || Arc::clone(&jwt_verifier)
     });
 
     let ws_route = warp::path!("ws" / TableId)
         .and(warp::ws())
-        .and(shared_tables_filter)
-        .and(db_cli_filter)
-        .and(next_client_id_filter)
-        .map(|table_id, ws: warp::ws::Ws, shared_tables, db_cli, next_client_id| {
-            ws.on_upgrade(move |socket| handle_connection(socket, shared_tables, table_id, db_cli, next_client_id))
+        .and(warp::query::<ConnectParams>())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(jwt_verifier_filter)
+        .and(server_filter)
+        .then(|table_id, ws: warp::ws::Ws, params: ConnectParams, protocol_header: Option<String>, jwt_verifier: Arc<JwtVerifier>, server: ServerContext| async move {
+            if server.shutdown.is_cancelled() {
+                return warp::reply::with_status("server is shutting down", StatusCode::SERVICE_UNAVAILABLE).into_response();
+            }
+
+            // The token may be passed either as a query parameter or as a subprotocol
+            let header_token = protocol_header.as_deref().and_then(token_from_protocol_header);
+            let uses_bearer_protocol = params.token.is_none() && header_token.is_some();
+
+            let user = match jwt_verifier.verify(params.token.as_deref().or(header_token)) {
+                Ok(user) => user,
+                Err(e) => {
+                    warn!("Rejected connection to table {}: {}", table_id, e);
+                    return warp::reply::with_status("unauthorized", StatusCode::UNAUTHORIZED).into_response();
+                }
+            };
+
+            // Only the table owner and users the table has been shared with may open it
+            let role = match table_role(&server.db, table_id, user.user_id).await {
+                Ok(Some(role)) => role,
+                Ok(None) => {
+                    warn!("Rejected connection to table {}: user {} has no access", table_id, user.user_id);
+                    return warp::reply::with_status("forbidden", StatusCode::FORBIDDEN).into_response();
+                },
+                Err(e) => {
+                    error!("could not check access to table {}: {}", table_id, e);
+                    return warp::reply::with_status("internal server error", StatusCode::INTERNAL_SERVER_ERROR).into_response();
+                }
+            };
+
+            let connections = server.connections.clone();
+            let reply = ws.on_upgrade(move |socket| connections.track_future(handle_connection(socket, params, user, role, table_id, server)));
+
+            // Browsers drop the connection unless the server selects one of the offered subprotocols
+            if uses_bearer_protocol {
+                warp::reply::with_header(reply, "sec-websocket-protocol", BEARER_PROTOCOL).into_response()
+            } else {
+                reply.into_response()
+            }
         });
 
-    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
-    println!("Rust WebSocket server running at ws://{}", addr);
-    warp::serve(ws_route).run(addr).await;
+    // Stop accepting connections on SIGTERM, then tell connected clients to reconnect later
+    let shutdown_on_signal = {
+        let shutdown = shutdown.clone();
+
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down");
+            shutdown.cancel();
+        }
+    };
+
+    match tls_cert {
+        None => {
+            let (addr, server) = warp::serve(ws_route).bind_with_graceful_shutdown(config.listen_addr, shutdown_on_signal);
+
+            info!("Rust WebSocket server running at ws://{}", addr);
+            server.await;
+        },
+        Some((tls_cert, server_config)) => {
+            let listener = match TcpListener::bind(config.listen_addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("could not listen on {} -- {}", config.listen_addr, e);
+                    return;
+                }
+            };
+
+            tokio::spawn(tls_cert.run_reloader(shutdown.clone()));
+
+            info!("Rust WebSocket server running at wss://{}", listener.local_addr().unwrap_or(config.listen_addr));
+            warp::serve(ws_route)
+                .serve_incoming_with_graceful_shutdown(accept_tls(listener, server_config), shutdown_on_signal)
+                .await;
+        }
+    }
+
+    drain(connections, shared_tables, &db, config.shutdown).await;
+}
+
+// === run_lock_manager ===========================================================================
+//
+// Releases the locks of a table as they expire. Rather than polling, the task sleeps until the
+// earliest deadline in the table's LockSchedule (or of its exclusive locks), and is woken early
+// whenever a lock is scheduled to expire before that. Only expired locks are ever visited, so a
+// table with no locks costs nothing. The task stops once the table is evicted.
+//
+// Clients push deadlines back by acquiring, renewing or editing cells; a deadline that has moved
+// by the time the task wakes is simply slept on again.
+//
+// ================================================================================================
+async fn run_lock_manager(table_ref: SharedTableRef, wake: Arc<Notify>, lifecycle: CancellationToken, table_id: TableId) {
+    loop {
+        let next_deadline = expire_locks(&mut *table_ref.lock().await, table_id).await;
+        let next_wake = async {
+            match next_deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await
+            }
+        };
+
+        tokio::select! {
+            _ = lifecycle.cancelled() => break,
+            _ = next_wake => {},
+            _ = wake.notified() => {}
+        }
+    }
 }
 
 // === handle_connection ==========================================================================
@@ -279,148 +680,127 @@ async fn main() {
 //      a. If not present, terminate
 //      b. Add database to table map
 //          i. Set client count to 0
-//          ii. Spawn lock manager thread
+//          ii. Spawn lock manager task
 //          iii. Create broadcast channel
 //  3. Increment client count on table
 //  4. Subscribe to broadcast channel
 //  5. Take messages until disconnect
 //  6. Decrement client count
+//  7. Once the table has no clients, evict it after the grace period
 //
 // ================================================================================================
-async fn handle_connection(ws: WebSocket, shared_tables: SharedTablesMap, table_id: TableId, db_cli_ref: Arc<Mutex<postgres::Client>>, next_client_id: SharedClientId) {
+async fn handle_connection(ws: WebSocket, params: ConnectParams, user: AuthenticatedUser, role: Role, table_id: TableId, server: ServerContext) {
+    let ServerContext { shared_tables, db, residency, write_behind, broadcast_capacity, lock_defaults, journal, shutdown_policy, shutdown, .. } = server;
+
+    // The table's slot stays held until the client has been counted, so that the table can neither
+    // be loaded twice nor evicted before the client is registered with it. The map itself is only
+    // locked to look the slot up.
+    let slot = claim_slot(&shared_tables, table_id).await;
+    let mut slot_guard = slot.lock().await;
+
     // Pseudocode:
     //  1. Check for table in map
-    let mut shared_table_ref : Option<SharedTableRef> = match shared_tables.lock().await.get(&table_id) {
-        None => None,
     //      a. If present, proceed to AAA
-        Some(table_ref) => Some(Arc::clone(table_ref))
-    };
+    let mut shared_table_ref : Option<SharedTableRef> = slot_guard.as_ref().map(Arc::clone);
+    let was_resident = shared_table_ref.is_some();
 
     // If the table is not yet held in the in-memory shared table map, fetch it from the database.
-    if let None = shared_table_ref {
-        let db_cli = db_cli_ref.lock().await;
+    if shared_table_ref.is_none() {
+        let fetched: Result<(FetchedTable, TableJournal), Box<dyn Error + Send + Sync>> = async {
+            // Edits journaled while the table was last loaded must reach the database before the
+            // table is read back from it
+            journal.replay(table_id, &db).await?;
+
+            let db_cli = db.client().await?;
+            let fetched = fetch_table(&db_cli, table_id, lock_defaults).await?;
 
-        match fetch_table(&db_cli, table_id).await {
-            Ok((table_cells, n_rows, n_cols)) => {
-                let (tx, _rx) = broadcast::channel::<ServerSocketMessage>(100);
+            Ok((fetched, journal.open(table_id).await?))
+        }.await;
+
+        match fetched {
+            Ok((FetchedTable { cells: table_cells, protected_ranges, lock_policy, n_rows, n_cols }, table_journal)) => {
+                let (tx, _rx) = broadcast::channel::<ServerSocketMessage>(broadcast_capacity);
                 //      b. Add table to table map
                 //          i. Set client count to 0
-                //          ii. TODO: Spawn lock manager thread
+                //          ii. Spawn lock manager task
                 //          iii. Create broadcast channel
+                let lock_expiry = Arc::new(Notify::new());
+                let dirty_threshold = Arc::new(Notify::new());
+                let lifecycle = CancellationToken::new();
                 let shared_table_new = Arc::new(Mutex::new(SharedTable{
-                    n_rows: n_rows,
-                    n_cols: n_cols,
+                    n_rows,
+                    n_cols,
                     cells: table_cells,
+                    protected_ranges,
+                    lock_policy,
+                    locks: Mutex::new(LockSchedule::new(Arc::clone(&lock_expiry))),
+                    flush: Arc::new(Mutex::new(())),
+                    dirty: Arc::new(Mutex::new(DirtyCells::new(write_behind.max_dirty_cells, Arc::clone(&dirty_threshold)))),
+                    journal: Arc::new(Mutex::new(table_journal)),
+                    next_range_id: 0,
+                    exclusive_locks: Vec::new(),
                     client_count: 0,
+                    client_sessions: HashMap::new(),
+                    idle_since: None,
+                    lifecycle: lifecycle.clone(),
                     sender: tx.clone()
                 }));
 
-                // === Lock Manager Thread ========================================================
-                //
-                // Scans all cells in the table at a one second interval. If a cell is currently
-                // locked by a certain user, the time remaining on their lock is decremented by
-                // one. If the time remaining reaches zero, the user's lock is released.
-                //
-                // When the main connection thread receives an action from the user, it should
-                // reset the time remaining to the initial value, extending the duration of the
-                // user's lock.
-                //
-                // ================================================================================
-                {
-                    let db_cli_clone = Arc::clone(&db_cli_ref);
-                    let shared_table_clone = Arc::clone(&shared_table_new);
-                    let tx_clone = tx.clone();
-
-                    thread::spawn(move || {
-                        block_on(async move {
-                            loop {
-                                {
-                                    let shared_table = shared_table_clone.lock().await;
-                                    let table_height = shared_table.n_rows;
-                                    let table_width = shared_table.n_cols;
-
-                                    for row in 0..table_height {
-                                        for col in 0..table_width {
-                                            let mut cell = shared_table.cells[row][col].lock().await;
-                                            let mut to_reset = false;
-
-                                            if let Some(ref mut lock) = cell.lock {
-                                                if lock.duration_secs < 2 {
-                                                    to_reset = true;
-                                                } else {
-                                                    lock.duration_secs -= 1;
-                                                }
-                                            }
-
-                                            if to_reset {
-                                                eprintln!("Resetting lock ({}, {})", row, col);
-                                                // write back to database
-                                                let db_cli = db_cli_clone.lock().await;
-
-                                               match db_cli.execute(
-                                                    "UPDATE table_cells SET text = $1 WHERE table_id = $2 AND row_num = $3 AND column_num = $4",
-                                                    &[&cell.text, &table_id, &(row as i32), &(col as i32)]
-                                                ).await {
-                                                   Ok(n_rows) => {
-                                                       println!("{} rows updated by update", n_rows);
-                                                   },
-                                                   Err(e) => {
-                                                       println!("Could not update table cell: {}", e);
-                                                   }
-                                               };
-                                                cell.lock = None;
-                                                let _ = tx_clone.send(ServerSocketMessage::ReleaseLock{
-                                                    cell: (row, col)
-                                                });
-                                            }
-                                        }
-                                    }
-                                }
-
-                                thread::sleep(Duration::from_secs(1));
-                            }
-                        });
-                    });
-                }
+                tokio::spawn(run_lock_manager(Arc::clone(&shared_table_new), lock_expiry, lifecycle.clone(), table_id));
+                tokio::spawn(run_write_behind(Arc::clone(&shared_table_new), dirty_threshold, lifecycle, write_behind, table_id, db.clone()));
 
                 shared_table_ref = Some(Arc::clone(&shared_table_new));
-
-                {
-                    let mut shared_tables = shared_tables.lock().await;
-                    shared_tables.insert(table_id, Arc::clone(&shared_table_new));
-                }
+                *slot_guard = Some(shared_table_new);
             },
             Err(e) => {
     //      a. If not present, terminate
-                eprintln!("ERROR: {}", e);
+                error!("{}", e);
             }
         };// end match fetch_table(db_cli, table_id)
     }
 
+    //  3. Increment client count on table
+    if let Some(table_ref) = &shared_table_ref {
+        let mut table = table_ref.lock().await;
+
+        table.client_count += 1;
+        table.idle_since = None;
+    }
+
+    drop(slot_guard);
+    drop(slot);
+
+    match &shared_table_ref {
+        // Make room for the table, now that it has been loaded
+        Some(_) if !was_resident => {
+            tokio::spawn(enforce_resident_cap(Arc::clone(&shared_tables), residency, db.clone()));
+        },
+        Some(_) => {},
+        // Do not leave the slot of a table that could not be loaded behind
+        None => release_slot(&shared_tables, table_id).await
+    }
+
     match shared_table_ref {
         None => {
-            eprintln!("Could not access table with id = {}", table_id);
+            warn!("Could not access table with id = {}", table_id);
         },
         Some(table_ref) => {
             let (mut user_ws_tx, mut user_ws_rx) = ws.split();
-            let current_client_id;
+            // Clients are identified by the id of the authenticated user
+            let current_client_id = user.user_id;
             let mut rx;
+            let mut database_status = db.status();
+
+            info!("Client {} ({}) connected to table {}", current_client_id, user.username, table_id);
 
             {
                 let mut table = table_ref.lock().await;
 
-                //  3. Increment client count on table
-                table.client_count += 1;
+                *table.client_sessions.entry(current_client_id).or_insert(0) += 1;
 
                 //  4. Subscribe to broadcast channel
                 rx = table.sender.subscribe();
 
-                {
-                    let mut id_lock = next_client_id.lock().await;
-                    current_client_id = *id_lock;
-                    *id_lock += 1;
-                }
-
                 let init_table = {
                     let mut snapshot = vec![];
                     let table_cells = &table.cells;
@@ -431,10 +811,7 @@ async fn handle_connection(ws: WebSocket, shared_tables: SharedTablesMap, table_
                             let c = cell.lock().await;
                             snap_row.push(TableCellClientView{
                                 text: c.text.clone(),
-                                owner_id: match &c.lock {
-                                    None => None,
-                                    Some(lock) => Some(lock.owner_id)
-                                }
+                                owner_id: c.lock.as_ref().map(|lock| lock.owner_id)
                             });
                         }
                         snapshot.push(snap_row);
@@ -444,285 +821,158 @@ async fn handle_connection(ws: WebSocket, shared_tables: SharedTablesMap, table_
 
                 let init_msg = ServerSocketMessage::Init {
                     client_id: current_client_id,
+                    role,
+                    offset_unit: params.offset_unit,
+                    lock_policy: table.lock_policy,
                     table: init_table,
+                    protected_ranges: table.protected_ranges.clone(),
+                    exclusive_locks: table.exclusive_locks.iter().map(ExclusiveLock::view).collect(),
+                    database_status: *database_status.borrow_and_update()
                 };
                 let _ = user_ws_tx.send(Message::text(serde_json::to_string(&init_msg).unwrap())).await;
             }
 
-            let send_task = tokio::spawn(async move {
-                while let Ok(msg) = rx.recv().await {
-                    let json = serde_json::to_string(&msg).unwrap();
-                    if user_ws_tx.send(Message::text(json)).await.is_err() {
-                        break;
+            // Messages addressed only to this client (acknowledgements and errors)
+            let (direct_tx, mut direct_rx) = mpsc::unbounded_channel::<ServerSocketMessage>();
+            // Cancelled to make the server close the connection
+            let disconnect = CancellationToken::new();
+            // The client's current role; updated when its share changes
+            let (role_tx, role_rx) = watch::channel(role);
+
+            let offset_unit = params.offset_unit;
+            let mut send_task = tokio::spawn({
+                let disconnect = disconnect.clone();
+
+                async move {
+                    loop {
+                        // Flush messages addressed to this client before closing the connection
+                        let msg = tokio::select! {
+                            biased;
+                            Some(msg) = direct_rx.recv() => msg,
+                            _ = shutdown.cancelled() => {
+                                let msg = ServerSocketMessage::ServerShutdown {
+                                    reconnect_after_ms: shutdown_policy.reconnect_after.as_millis() as u64
+                                };
+                                let _ = user_ws_tx.send(Message::text(serde_json::to_string(&msg).unwrap())).await;
+                                let _ = user_ws_tx.send(Message::close()).await;
+                                break;
+                            },
+                            Ok(()) = database_status.changed() => ServerSocketMessage::DatabaseStatus {
+                                status: *database_status.borrow_and_update()
+                            },
+                            _ = disconnect.cancelled() => {
+                                let _ = user_ws_tx.send(Message::close()).await;
+                                break;
+                            },
+                            msg = rx.recv() => match msg {
+                                Ok(msg) => localize_message(msg, offset_unit),
+                                Err(_) => break
+                            }
+                        };
+                        let json = serde_json::to_string(&msg).unwrap();
+                        if user_ws_tx.send(Message::text(json)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+
+            // Periodically re-check the user's role on the table
+            let mut access_task = tokio::spawn({
+                let db = db.clone();
+                let direct_tx = direct_tx.clone();
+                let disconnect = disconnect.clone();
+
+                async move {
+                    let mut interval = tokio::time::interval(ACCESS_CHECK_INTERVAL);
+
+                    // The first tick completes immediately; access was just checked on upgrade
+                    interval.tick().await;
+
+                    loop {
+                        interval.tick().await;
+
+                        match table_role(&db, table_id, current_client_id).await {
+                            Ok(Some(role)) => {
+                                role_tx.send_if_modified(|current| {
+                                    if *current == role {
+                                        return false;
+                                    }
+                                    info!("Client {} is now {:?} on table {}", current_client_id, role, table_id);
+                                    *current = role;
+                                    true
+                                });
+                            },
+                            Ok(None) => {
+                                info!("Access to table {} revoked for client {}", table_id, current_client_id);
+                                direct_tx.send(ServerSocketMessage::Error {
+                                    request_id: None,
+                                    code: ErrorCode::Forbidden,
+                                    message: String::from("access to this table has been revoked")
+                                }).ok();
+                                disconnect.cancel();
+                                break;
+                            },
+                            Err(e) => {
+                                // Keep the connection open while the database is unreachable
+                                error!("could not re-check access to table {}: {}", table_id, e);
+                            }
+                        }
                     }
                 }
             });
 
+            let session = Arc::new(ClientSession {
+                client_id: current_client_id,
+                role: role_rx,
+                offset_unit,
+                table_id,
+                table_ref: Arc::clone(&table_ref),
+                db: db.clone()
+            });
+
             //  5. Take messages until disconnect
-            let recv_task = tokio::spawn({
-                let table_ref = Arc::clone(&table_ref);
+            let mut recv_task = tokio::spawn({
+                let session = Arc::clone(&session);
+
                 async move {
                     while let Some(Ok(msg)) = user_ws_rx.next().await {
-                        if let Ok(text_str) = msg.to_str() {
-                            if let Ok(client_msg) = serde_json::from_str::<ClientSocketMessage>(text_str) {
-                                match client_msg {
-                                    ClientSocketMessage::Insert { cell: (r, c), index, ref text } => {
-                                        let cell_ref = {
-                                            let table = table_ref.lock().await;
-
-                                            Arc::clone(&table.cells[r][c])
-                                        };
-                                        let mut cell = cell_ref.lock().await;
-
-                                        if cell.lock.map_or(true, |l| l.owner_id == current_client_id) {
-                                            if index >= cell.text.len() {
-                                                cell.text.push_str(text);
-                                            } else {
-                                                cell.text.insert_str(index, text);
-                                            }
-                                            cell.lock = Some(CellLockData { owner_id: current_client_id, duration_secs: 3 });
-
-                                            {
-                                                let table = table_ref.lock().await;
-
-                                                // table.sender.send(client_msg).ok();
-                                                table.sender.send(ServerSocketMessage::Insert{
-                                                    client_id: current_client_id,
-                                                    cell: (r, c),
-                                                    index: index,
-                                                    text: text.clone()
-                                                }).ok();
-                                                table.sender.send(ServerSocketMessage::AcquireLock {
-                                                    client_id: current_client_id, cell: (r, c)
-                                                }).ok();
-                                            }
-                                        }
-                                    }
-                                    ClientSocketMessage::Delete { cell: (r, c), start, end } => {
-                                        let cell_ref = {
-                                            let table = table_ref.lock().await;
-
-                                            Arc::clone(&table.cells[r][c])
-                                        };
-                                        let mut cell = cell_ref.lock().await;
-
-                                        if cell.lock.map_or(true, |l| l.owner_id == current_client_id) {
-                                            if start <= end && end <= cell.text.len() {
-                                                cell.text.replace_range(start..end, "");
-                                                cell.lock = Some(CellLockData { owner_id: current_client_id, duration_secs: 3 });
-
-                                                {
-                                                    let table = table_ref.lock().await;
-
-                                                    table.sender.send(ServerSocketMessage::Delete{
-                                                        client_id: current_client_id,
-                                                        cell: (r, c),
-                                                        start: start,
-                                                        end: end
-                                                    }).ok();
-                                                    table.sender.send(ServerSocketMessage::AcquireLock{
-                                                        client_id: current_client_id,
-                                                        cell: (r, c)
-                                                    }).ok();
-                                                }
-                                            }
-                                        }
-                                    }
-                                    ClientSocketMessage::Replace { cell: (r, c), start, end, ref text } => {
-                                        let cell_ref = {
-                                            let table = table_ref.lock().await;
-
-                                            Arc::clone(&table.cells[r][c])
-                                        };
-                                        let mut cell = cell_ref.lock().await;
-                                        if cell.lock.map_or(true, |l| l.owner_id == current_client_id) {
-                                            if start <= end && end <= cell.text.len() {
-                                                cell.text.replace_range(start..end, text);
-                                                cell.lock = Some(CellLockData { owner_id: current_client_id, duration_secs: 3 });
-
-                                                {
-                                                    let table = table_ref.lock().await;
-
-                                                    table.sender.send(ServerSocketMessage::Replace{
-                                                        client_id: current_client_id,
-                                                        cell: (r, c),
-                                                        start: start,
-                                                        end: end,
-                                                        text: text.clone()
-                                                    }).ok();
-                                                    table.sender.send(ServerSocketMessage::AcquireLock{
-                                                        client_id: current_client_id,
-                                                        cell: (r, c)
-                                                    }).ok();
-                                                }
-                                            }
-                                        }
-                                    },
-                                    ClientSocketMessage::InsertRows { insertion_index, num_rows } => {
-                                        // Update table in-memory
-                                        let mut table = table_ref.lock().await;
-
-                                        if insertion_index > table.n_rows {
-                                            // invalid insertion index
-                                            // TODO: return insertion index
-                                            eprintln!(
-                                                "ERROR: insertion index ({}) > table height ({})",
-                                                insertion_index, table.n_rows
-                                            );
-                                            continue;
-                                        }
-
-                                        let n_rows_orig = table.n_rows;
-                                        let n_cols = table.n_cols;
-
-                                        table.n_rows += num_rows;
-
-                                        for _ in 0..num_rows {
-                                            let mut new_row = Vec::<Arc<Mutex<TableCell>>>::new();
-
-                                            for _ in 0..n_cols {
-                                                new_row.push(Arc::new(Mutex::new(TableCell{
-                                                    text: String::new(),
-                                                    lock: None
-                                                })));
-                                            }// end for _ in 0..n_cols
-                                            table.cells.insert(insertion_index, new_row);
-                                        }// end for _ in 0..num_rows
-
-                                        // TODO: Make a database transaction
-                                        {
-                                            // Update table dimensions
-                                            let db_cli = db_cli_ref.lock().await;
-
-                                            match db_cli.execute("UPDATE tables SET height = height + $1 WHERE id = $2", &[&(num_rows as i32), &table_id]).await {
-                                                Ok(n_rows) => {
-                                                   println!("{} rows updated by update", n_rows);
-                                                },
-                                                Err(e) => {
-                                                    println!("Could not update table: {}", e);
-                                                }
-                                            };
-
-                                            // increment row number of all existing cells at or
-                                            // above insertion row
-                                            for i_row in (insertion_index..n_rows_orig).rev() {
-                                                match db_cli.execute("UPDATE table_cells SET row_num = row_num + $1 WHERE table_id = $2 AND row_num = $3", &[&(num_rows as i32), &table_id, &(i_row as i32)]).await {
-                                                    Ok(n_rows) => {
-                                                       println!("{} rows updated by update", n_rows);
-                                                    },
-                                                    Err(e) => {
-                                                        println!("Could not update table: {}", e);
-                                                    }
-                                                };
-                                            }
-
-                                            // insert new table cells
-                                            let new_text = String::new();
-                                            for idx in 0..num_rows {
-                                                let i_row = insertion_index + idx;
-
-                                                for i_col in 0..n_cols {
-                                                    match db_cli.execute("INSERT INTO table_cells (table_id, row_num, column_num, text) VALUES ($1, $2, $3, $4)", &[&table_id, &(i_row as i32), &(i_col as i32), &new_text]).await {
-                                                        Ok(n_rows) => {
-                                                           println!("{} rows updated by insertion", n_rows);
-                                                        },
-                                                        Err(e) => {
-                                                            println!("Could not update table cells: {}", e);
-                                                        }
-                                                    };
-                                                }// end for i_col in 0..n_cols
-                                            }// end for idx in 0..n_rows
-                                        }
-
-                                        // Update clients
-                                        table.sender.send(ServerSocketMessage::InsertRows{
-                                            client_id: current_client_id,
-                                            insertion_index: insertion_index,
-                                            num_rows: num_rows
-                                        }).ok();
-                                    },
-                                    ClientSocketMessage::InsertCols { insertion_index, num_cols } => {
-                                        // Update table in-memory
-                                        let mut table = table_ref.lock().await;
-
-                                        if insertion_index > table.n_cols {
-                                            // invalid insertion index
-                                            // TODO: return insertion index
-                                            eprintln!(
-                                                "ERROR: insertion index ({}) > table width ({})",
-                                                insertion_index, table.n_cols
-                                            );
-                                            continue;
-                                        }
-
-                                        let n_cols_orig = table.n_cols;
-
-                                        table.n_cols += num_cols;
-
-                                        for i_row in 0..table.n_rows {
-                                            let row = &mut table.cells[i_row];
-
-                                            for _ in 0..num_cols {
-                                                row.insert(insertion_index, Arc::new(Mutex::new(TableCell{
-                                                    text: String::new(),
-                                                    lock: None
-                                                })));
-                                            }// end for _ in 0..num_cols
-                                        }// end for &mut row in table.cells.iter_mut()
-
-                                        // TODO: Make a database transaction
-                                        {
-                                            // Update table dimensions
-                                            let db_cli = db_cli_ref.lock().await;
-
-                                            match db_cli.execute("UPDATE tables SET width = width + $1 WHERE id = $2", &[&(num_cols as i32), &table_id]).await {
-                                                Ok(n_rows) => {
-                                                   println!("{} rows updated by update", n_rows);
-                                                },
-                                                Err(e) => {
-                                                    println!("Could not update table: {}", e);
-                                                }
-                                            };
-
-                                            // increment row number of all existing cells at or
-                                            // above insertion row
-                                            for i_col in (insertion_index..n_cols_orig).rev() {
-                                                match db_cli.execute("UPDATE table_cells SET column_num = column_num + $1 WHERE table_id = $2 AND column_num = $3", &[&(num_cols as i32), &table_id, &(i_col as i32)]).await {
-                                                    Ok(n_rows) => {
-                                                       println!("{} rows updated by update", n_rows);
-                                                    },
-                                                    Err(e) => {
-                                                        println!("Could not update table: {}", e);
-                                                    }
-                                                };
-                                            }
-
-                                            // insert new table cells
-                                            let new_text = String::new();
-                                            for i_row in 0..table.n_rows {
-                                                for idx in 0..num_cols {
-                                                    let i_col = insertion_index + idx;
-
-                                                    match db_cli.execute("INSERT INTO table_cells (table_id, row_num, column_num, text) VALUES ($1, $2, $3, $4)", &[&table_id, &(i_row as i32), &(i_col as i32), &new_text]).await {
-                                                        Ok(n_rows) => {
-                                                           println!("{} rows updated by insertion", n_rows);
-                                                        },
-                                                        Err(e) => {
-                                                            println!("Could not update table cells: {}", e);
-                                                        }
-                                                    };
-                                                }// end for i_col in 0..n_cols
-                                            }// end for idx in 0..n_rows
-                                        }
-
-                                        // Update clients
-                                        table.sender.send(ServerSocketMessage::InsertCols{
-                                            client_id: current_client_id,
-                                            insertion_index: insertion_index,
-                                            num_cols: num_cols
-                                        }).ok();
-                                    }
+                        // Ignore non-text frames (ping, pong, close)
+                        let Ok(text_str) = msg.to_str() else {
+                            continue;
+                        };
+
+                        let request = match serde_json::from_str::<ClientRequest>(text_str) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                error!("could not parse message from client {}: {}", current_client_id, e);
+                                // Salvage the request id, if any, so the client can match the error
+                                let request_id = serde_json::from_str::<serde_json::Value>(text_str)
+                                    .ok()
+                                    .and_then(|value| value.get("request_id")?.as_u64());
+
+                                direct_tx.send(ServerSocketMessage::Error {
+                                    request_id,
+                                    code: ErrorCode::ParseError,
+                                    message: e.to_string()
+                                }).ok();
+                                continue;
+                            }
+                        };
+
+                        match handle_client_message(&session, request.message).await {
+                            Ok(()) => {
+                                if let Some(request_id) = request.request_id {
+                                    direct_tx.send(ServerSocketMessage::Ack { request_id }).ok();
                                 }
+                            },
+                            Err(e) => {
+                                error!("rejected message from client {}: {}", current_client_id, e);
+                                direct_tx.send(ServerSocketMessage::Error {
+                                    request_id: request.request_id,
+                                    code: e.code,
+                                    message: e.message
+                                }).ok();
                             }
                         }
                     }
@@ -730,8 +980,18 @@ async fn handle_connection(ws: WebSocket, shared_tables: SharedTablesMap, table_
             });
 
             tokio::select! {
-                _ = send_task => {},
-                _ = recv_task => {},
+                _ = &mut send_task => {},
+                _ = &mut recv_task => {},
+                _ = &mut access_task => {},
+            }
+
+            recv_task.abort();
+            access_task.abort();
+            disconnect.cancel();
+
+            // Give the send task a moment to flush pending messages and close the socket
+            if tokio::time::timeout(Duration::from_secs(5), &mut send_task).await.is_err() {
+                send_task.abort();
             }
 
             //  6. Decrement client count
@@ -739,9 +999,27 @@ async fn handle_connection(ws: WebSocket, shared_tables: SharedTablesMap, table_
                 let mut table = table_ref.lock().await;
 
                 table.client_count -= 1;
+
+                let n_sessions = table.client_sessions.get_mut(&current_client_id).map(|n_sessions| {
+                    *n_sessions -= 1;
+                    *n_sessions
+                });
+
+                // Once the client's last connection closes, release its locks right away rather
+                // than waiting for them to expire
+                if n_sessions == Some(0) {
+                    table.client_sessions.remove(&current_client_id);
+                    release_client_locks(&mut table, &session).await;
+                    table.sender.send(ServerSocketMessage::ClientLeft { client_id: current_client_id }).ok();
+                }
+
+                if table.client_count == 0 {
+                    table.idle_since = Some(Instant::now());
+                    schedule_eviction(Arc::clone(&shared_tables), table_id, residency, db.clone());
+                }
             }
 
-            println!("Client {} disconnected", current_client_id);
+            info!("Client {} disconnected", current_client_id);
         }
     };// end match &shared_tables.get(&table_id)
     //  3. Increment client count on table
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::ServerSocketMessage;
+
+// === OffsetUnit =================================================================================
+//
+// The unit in which a client expresses text offsets (`index`, `start`, `end`) within a cell.
+// Negotiated once per connection; the server stores cell text as UTF-8 and converts offsets at
+// the protocol boundary.
+//
+// - byte: UTF-8 code units (the server's native representation)
+// - utf16: UTF-16 code units, as used by JavaScript strings
+// - scalar: Unicode scalar values (Rust chars, Python str indices)
+// - grapheme: Extended grapheme clusters (user-perceived characters)
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OffsetUnit {
+    #[default]
+    Byte,
+    Utf16,
+    Scalar,
+    Grapheme,
+}
+
+// Converts an offset expressed in `unit` into a UTF-8 byte offset into `text`. Returns None if the
+// offset lies beyond the end of the text or does not fall on a boundary of the server's
+// representation (e.g. between the two halves of a UTF-16 surrogate pair).
+pub(crate) fn to_byte_offset(text: &str, offset: usize, unit: OffsetUnit) -> Option<usize> {
+    match unit {
+        OffsetUnit::Byte => text.is_char_boundary(offset).then_some(offset),
+        OffsetUnit::Utf16 => {
+            let mut n_units = 0;
+
+            for (i_byte, ch) in text.char_indices() {
+                if n_units == offset {
+                    return Some(i_byte);
+                } else if n_units > offset {
+                    return None;
+                }
+                n_units += ch.len_utf16();
+            }
+
+            (n_units == offset).then_some(text.len())
+        },
+        OffsetUnit::Scalar => nth_boundary(text.char_indices().map(|(i_byte, _)| i_byte), text.len(), offset),
+        OffsetUnit::Grapheme => nth_boundary(text.grapheme_indices(true).map(|(i_byte, _)| i_byte), text.len(), offset)
+    }
+}
+
+// Converts a UTF-8 byte offset into `text` into an offset expressed in `unit`. The byte offset is
+// expected to fall on a char boundary. A byte offset falling inside a grapheme cluster maps to the
+// index of that cluster.
+pub(crate) fn from_byte_offset(text: &str, offset: usize, unit: OffsetUnit) -> usize {
+    let offset = offset.min(text.len());
+
+    match unit {
+        OffsetUnit::Byte => offset,
+        OffsetUnit::Utf16 => text[..offset].encode_utf16().count(),
+        OffsetUnit::Scalar => text[..offset].chars().count(),
+        OffsetUnit::Grapheme => text.grapheme_indices(true)
+            .take_while(|(i_byte, grapheme)| i_byte + grapheme.len() <= offset)
+            .count()
+    }
+}
+
+fn nth_boundary(mut starts: impl Iterator<Item = usize>, len: usize, n: usize) -> Option<usize> {
+    let mut count = 0;
+
+    for start in starts.by_ref() {
+        if count == n {
+            return Some(start);
+        }
+        count += 1;
+    }
+
+    (count == n).then_some(len)
+}
+
+// === localize_message ===========================================================================
+//
+// Rewrites the text offsets of a broadcast message, which the server expresses in bytes relative
+// to the cell text before the edit, into the offset unit of the receiving client.
+//
+// ================================================================================================
+pub(crate) fn localize_message(message: ServerSocketMessage, unit: OffsetUnit) -> ServerSocketMessage {
+    if unit == OffsetUnit::Byte {
+        return message;
+    }
+
+    match message {
+        ServerSocketMessage::Insert { client_id, cell, index, text, text_before } => ServerSocketMessage::Insert {
+            client_id,
+            cell,
+            index: from_byte_offset(&text_before, index, unit),
+            text,
+            text_before
+        },
+        ServerSocketMessage::Delete { client_id, cell, start, end, text_before } => ServerSocketMessage::Delete {
+            client_id,
+            cell,
+            start: from_byte_offset(&text_before, start, unit),
+            end: from_byte_offset(&text_before, end, unit),
+            text_before
+        },
+        ServerSocketMessage::Replace { client_id, cell, start, end, text, text_before } => ServerSocketMessage::Replace {
+            client_id,
+            cell,
+            start: from_byte_offset(&text_before, start, unit),
+            end: from_byte_offset(&text_before, end, unit),
+            text,
+            text_before
+        },
+        message => message
+    }
+}
+
+// Normalizes cell text to NFC before it is written to the database. The in-memory text is left
+// untouched so that offsets held by connected clients remain valid.
+pub(crate) fn normalize_for_storage(text: &str) -> String {
+    text.nfc().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "e" + combining acute accent, a CJK character, and an emoji outside the BMP
+    const TEXT: &str = "ae\u{301}\u{4e2d}\u{1f600}z";
+
+    #[test]
+    fn converts_offsets_into_bytes() {
+        let units = [
+            (OffsetUnit::Byte, vec![0, 1, 2, 4, 7, 11, 12]),
+            (OffsetUnit::Utf16, vec![0, 1, 2, 3, 4, 6, 7]),
+            (OffsetUnit::Scalar, vec![0, 1, 2, 3, 4, 5, 6]),
+        ];
+        let bytes = [0, 1, 2, 4, 7, 11, 12];
+
+        for (unit, offsets) in units {
+            for (offset, byte) in offsets.into_iter().zip(bytes) {
+                assert_eq!(to_byte_offset(TEXT, offset, unit), Some(byte), "{:?} offset {}", unit, offset);
+                assert_eq!(from_byte_offset(TEXT, byte, unit), offset, "{:?} byte {}", unit, byte);
+            }
+        }
+
+        // "e" and its combining accent form a single grapheme cluster
+        for (offset, byte) in [(0, 0), (1, 1), (2, 4), (3, 7), (4, 11), (5, 12)] {
+            assert_eq!(to_byte_offset(TEXT, offset, OffsetUnit::Grapheme), Some(byte));
+            assert_eq!(from_byte_offset(TEXT, byte, OffsetUnit::Grapheme), offset);
+        }
+
+        // Between "e" and its combining accent, i.e. inside the cluster at index 1
+        assert_eq!(from_byte_offset(TEXT, 2, OffsetUnit::Grapheme), 1);
+    }
+
+    #[test]
+    fn rejects_offsets_outside_text_or_inside_characters() {
+        assert_eq!(to_byte_offset(TEXT, 3, OffsetUnit::Byte), None);
+        assert_eq!(to_byte_offset(TEXT, 13, OffsetUnit::Byte), None);
+        // between the two halves of the emoji's surrogate pair
+        assert_eq!(to_byte_offset(TEXT, 5, OffsetUnit::Utf16), None);
+        assert_eq!(to_byte_offset(TEXT, 8, OffsetUnit::Utf16), None);
+        assert_eq!(to_byte_offset(TEXT, 7, OffsetUnit::Scalar), None);
+        assert_eq!(to_byte_offset(TEXT, 6, OffsetUnit::Grapheme), None);
+        assert_eq!(to_byte_offset(TEXT, usize::MAX, OffsetUnit::Grapheme), None);
+        assert_eq!(to_byte_offset("", 0, OffsetUnit::Utf16), Some(0));
+        assert_eq!(to_byte_offset("", 1, OffsetUnit::Scalar), None);
+    }
+
+    #[test]
+    fn localizes_broadcast_offsets() {
+        let message = ServerSocketMessage::Replace {
+            client_id: 1,
+            cell: (0, 0),
+            start: 7,
+            end: 11,
+            text: "!".into(),
+            text_before: TEXT.into()
+        };
+
+        match localize_message(message, OffsetUnit::Utf16) {
+            ServerSocketMessage::Replace { start, end, .. } => assert_eq!((start, end), (4, 6)),
+            message => panic!("unexpected message {:?}", message)
+        }
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        assert_eq!(normalize_for_storage("e\u{301}"), "\u{e9}");
+        assert_eq!(normalize_for_storage("\u{e9}"), "\u{e9}");
+    }
+}
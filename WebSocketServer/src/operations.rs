@@ -0,0 +1,1610 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::Instant,
+    error::Error,
+    fmt
+};
+
+use futures::lock::{Mutex, MutexGuard};
+use tokio::sync::watch;
+use tokio_postgres::types::ToSql;
+use log::{debug, error, info};
+
+use crate::{
+    CellLockData,
+    ClientSocketMessage,
+    ErrorCode,
+    ServerSocketMessage,
+    SharedTable,
+    SharedTableRef,
+    TableCell,
+    TableId,
+    access::Role,
+    database::{Database, DatabaseError},
+    locking::{ExclusiveLock, ExclusiveScope, Lease, LockGranularity, RangeLockData, RangeLockId},
+    offsets::OffsetUnit,
+    persistence::flush_dirty_cells,
+    protection::{ProtectedRange, shift_protected_ranges},
+    structure::{Axis, StructuralChange},
+    validation::{resolve_text_offset, resolve_text_range, validate_message}
+};
+
+// === ClientSession ==============================================================================
+//
+// Everything an operation needs to know about the client that requested it.
+//
+// - client_id: The id of the requesting client
+// - role: The client's current role on the table (kept up to date by the connection)
+// - offset_unit: The unit in which the client expresses text offsets
+// - table_id: The id of the table the client is connected to
+// - table_ref: The in-memory table the client is connected to
+// - db: The database connection pool
+//
+// ================================================================================================
+pub(crate) struct ClientSession {
+    pub(crate) client_id: u64,
+    pub(crate) role: watch::Receiver<Role>,
+    pub(crate) offset_unit: OffsetUnit,
+    pub(crate) table_id: TableId,
+    pub(crate) table_ref: SharedTableRef,
+    pub(crate) db: Database
+}
+
+// === OperationError =============================================================================
+//
+// Describes why a client operation was rejected. Sent back to the requesting client as a
+// ServerSocketMessage::Error.
+//
+// ================================================================================================
+#[derive(Debug, Clone)]
+pub(crate) struct OperationError {
+    pub(crate) code: ErrorCode,
+    pub(crate) message: String
+}
+
+impl OperationError {
+    pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for OperationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl Error for OperationError {}
+
+// === handle_client_message ======================================================================
+//
+// Applies a single client message to the shared table.
+//
+// Every message either edits the table or locks its cells, so clients whose role does not permit
+// editing (viewers and commenters) are rejected before anything else is checked.
+//
+// Messages are validated against the table dimensions before being dispatched, so the individual
+// operations may index into the table directly.
+//
+// The table lock is held for the duration of each operation so that cell coordinates cannot shift
+// underneath it (e.g. an edit racing a row deletion). Cell locks are always taken while holding
+// the table lock, never the other way around.
+//
+// ================================================================================================
+pub(crate) async fn handle_client_message(session: &ClientSession, message: ClientSocketMessage) -> Result<(), OperationError> {
+    let role = *session.role.borrow();
+
+    if !role.can_edit() {
+        return Err(OperationError::new(
+            ErrorCode::Forbidden,
+            format!("clients with the {:?} role may not edit this table", role)
+        ));
+    }
+
+    let mut table = session.table_ref.lock().await;
+
+    validate_message(&message, table.n_rows, table.n_cols)?;
+    check_exclusive_locks(&table, session, &message)?;
+
+    match message {
+        ClientSocketMessage::Insert { cell, index, text } => insert_text(&table, session, cell, index, text).await,
+        ClientSocketMessage::Delete { cell, start, end } => delete_text(&table, session, cell, start, end).await,
+        ClientSocketMessage::Replace { cell, start, end, text } => replace_text(&table, session, cell, start, end, text).await,
+        ClientSocketMessage::InsertRows { insertion_index, num_rows } => insert_rows(&mut table, session, insertion_index, num_rows).await,
+        ClientSocketMessage::InsertCols { insertion_index, num_cols } => insert_cols(&mut table, session, insertion_index, num_cols).await,
+        ClientSocketMessage::DeleteRows { start, count } => delete_rows(&mut table, session, start, count).await,
+        ClientSocketMessage::DeleteCols { start, count } => delete_cols(&mut table, session, start, count).await,
+        ClientSocketMessage::MoveRows { from, count, to } => move_rows(&mut table, session, from, count, to).await,
+        ClientSocketMessage::MoveCols { from, count, to } => move_cols(&mut table, session, from, count, to).await,
+        ClientSocketMessage::AcquireLock { cell } => acquire_lock(&table, session, cell).await,
+        ClientSocketMessage::ReleaseLock { cell } => release_lock(&table, session, cell).await,
+        ClientSocketMessage::RenewLock { cell } => renew_lock(&table, session, cell).await,
+        ClientSocketMessage::LockRange { top_left, bottom_right } => lock_range(&mut table, session, top_left, bottom_right).await,
+        ClientSocketMessage::AcquireExclusive { scope, force } => acquire_exclusive(&mut table, session, scope, force).await,
+        ClientSocketMessage::ReleaseExclusive { scope } => release_exclusive(&mut table, session, scope),
+        ClientSocketMessage::QueueLock { cell } => queue_lock(&table, session, cell).await,
+        ClientSocketMessage::BreakLock { cell } => break_lock(&table, session, cell).await
+    }
+}
+
+// Rejects messages touching the scope of another client's exclusive lock
+fn check_exclusive_locks(table: &SharedTable, session: &ClientSession, message: &ClientSocketMessage) -> Result<(), OperationError> {
+    // The rows the message touches; None for changes to the structure of the table
+    let rows = match *message {
+        ClientSocketMessage::Insert { cell, .. }
+            | ClientSocketMessage::Delete { cell, .. }
+            | ClientSocketMessage::Replace { cell, .. }
+            | ClientSocketMessage::AcquireLock { cell }
+            | ClientSocketMessage::RenewLock { cell }
+            | ClientSocketMessage::QueueLock { cell } => Some((cell.0, cell.0)),
+        ClientSocketMessage::LockRange { top_left, bottom_right } => Some((top_left.0, bottom_right.0)),
+        ClientSocketMessage::InsertRows { .. }
+            | ClientSocketMessage::InsertCols { .. }
+            | ClientSocketMessage::DeleteRows { .. }
+            | ClientSocketMessage::DeleteCols { .. }
+            | ClientSocketMessage::MoveRows { .. }
+            | ClientSocketMessage::MoveCols { .. } => None,
+        // Releasing locks is always allowed; exclusive locks check for conflicts themselves
+        ClientSocketMessage::ReleaseLock { .. }
+            | ClientSocketMessage::BreakLock { .. }
+            | ClientSocketMessage::AcquireExclusive { .. }
+            | ClientSocketMessage::ReleaseExclusive { .. } => return Ok(())
+    };
+
+    match table.exclusive_locks.iter().find(|exclusive| exclusive.owner_id != session.client_id && exclusive.covers(rows)) {
+        Some(exclusive) => Err(OperationError::new(
+            ErrorCode::LockConflict,
+            format!("{:?} is exclusively locked by client {}", exclusive.scope, exclusive.owner_id)
+        )),
+        None => Ok(())
+    }
+}
+
+fn check_cell_owner(cell: &TableCell, client_id: u64, (r, c): (usize, usize)) -> Result<(), OperationError> {
+    match cell.lock {
+        Some(lock) if lock.owner_id != client_id => Err(OperationError::new(
+            ErrorCode::LockConflict,
+            format!("cell ({}, {}) is locked by client {}", r, c, lock.owner_id)
+        )),
+        _ => Ok(())
+    }
+}
+
+type ScopeGuards<'a> = Vec<((usize, usize), MutexGuard<'a, TableCell>)>;
+
+// Takes the cell mutexes of every cell locked together with the cell at (r, c) under the table's
+// lock policy. The guard of (r, c) itself comes first.
+async fn lock_scope(table: &SharedTable, (r, c): (usize, usize)) -> ScopeGuards<'_> {
+    let scope = table.lock_policy.lock_scope((r, c), table.n_cols);
+    let mut guards = Vec::with_capacity(scope.len());
+
+    guards.push(((r, c), table.cells[r][c].lock().await));
+    for (i_row, i_col) in scope.into_iter().filter(|&pos| pos != (r, c)) {
+        guards.push(((i_row, i_col), table.cells[i_row][i_col].lock().await));
+    }
+
+    guards
+}
+
+fn check_scope_owner(guards: &ScopeGuards<'_>, client_id: u64) -> Result<(), OperationError> {
+    guards.iter().try_for_each(|(pos, cell)| check_cell_owner(cell, client_id, *pos))
+}
+
+fn is_held_by(cell: &TableCell, client_id: u64) -> bool {
+    cell.lock.is_some_and(|lock| lock.owner_id == client_id)
+}
+
+// Counts the lock units (cells or rows) the client holds outside of the given scope. A range lock
+// counts as a single unit.
+async fn count_held_locks(table: &SharedTable, client_id: u64, guards: &ScopeGuards<'_>) -> usize {
+    let mut held_cells = 0;
+    let mut held_rows = HashSet::new();
+    let mut held_ranges = HashSet::new();
+    let schedule = table.locks.lock().await;
+
+    for ((i_row, i_col), lease) in schedule.held_by(client_id) {
+        if guards.iter().any(|(pos, _)| *pos == (i_row, i_col)) {
+            continue;
+        }
+
+        match lease {
+            Lease::Range(range_id) => {
+                held_ranges.insert(range_id);
+            },
+            Lease::Until(_) => {
+                held_cells += 1;
+                held_rows.insert(i_row);
+            }
+        }
+    }
+
+    held_ranges.len() + match table.lock_policy.granularity {
+        LockGranularity::Cell => held_cells,
+        LockGranularity::Row => held_rows.len()
+    }
+}
+
+fn check_lock_limit(table: &SharedTable, n_held: usize) -> Result<(), OperationError> {
+    match table.lock_policy.max_locks_per_client {
+        Some(max_locks) if n_held >= max_locks as usize => Err(OperationError::new(
+            ErrorCode::LockLimitExceeded,
+            format!("client may not hold more than {} locks on this table", max_locks)
+        )),
+        _ => Ok(())
+    }
+}
+
+// Locks (or renews the lock on) every cell in the scope on behalf of the client, enforcing the
+// table's limit on the number of locks a client may hold
+async fn claim_lock(table: &SharedTable, session: &ClientSession, guards: &mut ScopeGuards<'_>) -> Result<(), OperationError> {
+    check_claim(table, session.client_id, guards).await?;
+    apply_claim(table, session.client_id, guards).await;
+    Ok(())
+}
+
+// Checks that the client may lock every cell in the scope, without locking anything yet
+async fn check_claim(table: &SharedTable, client_id: u64, guards: &ScopeGuards<'_>) -> Result<(), OperationError> {
+    check_scope_owner(guards, client_id)?;
+
+    let newly_acquired = guards.iter().any(|(_, cell)| !is_held_by(cell, client_id));
+
+    if newly_acquired && table.lock_policy.max_locks_per_client.is_some() {
+        check_lock_limit(table, count_held_locks(table, client_id, guards).await)?;
+    }
+
+    Ok(())
+}
+
+// Locks every cell in the scope on behalf of the client; check_claim must have passed
+async fn apply_claim(table: &SharedTable, client_id: u64, guards: &mut ScopeGuards<'_>) {
+    let deadline = table.lock_policy.lease_deadline();
+    let mut schedule = table.locks.lock().await;
+
+    for (pos, cell) in guards.iter_mut() {
+        match cell.lock {
+            // Cells of a range lock are renewed along with the rest of their range
+            Some(CellLockData { range_id: Some(range_id), .. }) => {
+                schedule.renew_range(range_id, deadline);
+            },
+            Some(_) => {
+                schedule.schedule_cell(*pos, client_id, Lease::Until(deadline));
+            },
+            None => {
+                cell.lock = Some(CellLockData { owner_id: client_id, range_id: None });
+                schedule.schedule_cell(*pos, client_id, Lease::Until(deadline));
+                table.sender.send(ServerSocketMessage::AcquireLock{
+                    client_id,
+                    cell: *pos
+                }).ok();
+            }
+        }
+    }
+}
+
+// === release_cell_lock ==========================================================================
+//
+// Releases the lock on a cell. If any client is queued for the cell, adds it to `queued`; the
+// caller must then hand it on with grant_queued once it has let go of every cell mutex.
+//
+// ================================================================================================
+pub(crate) async fn release_cell_lock(table: &SharedTable, cell: &mut TableCell, pos: (usize, usize), queued: &mut Vec<(usize, usize)>) {
+    cell.lock = None;
+    table.locks.lock().await.unschedule_cell(pos);
+
+    table.sender.send(ServerSocketMessage::ReleaseLock{
+        cell: pos
+    }).ok();
+
+    if !cell.waiters.is_empty() {
+        queued.push(pos);
+    }
+}
+
+// === grant_queued ===============================================================================
+//
+// Hands each of the given cells, once free, to the first client queued for it. The lock is claimed
+// exactly as if the client had acquired it itself, so it covers the client's whole lock scope
+// (e.g. the row) and counts towards the client's lock limit. A client whose scope is still partly
+// locked by someone else moves to the queue of that cell instead. A client that may not take the
+// lock, e.g. because it has reached its limit, leaves the queue with LockDenied and the next client
+// is tried.
+//
+// Must be called with the table locked but no cell mutex held.
+//
+// ================================================================================================
+pub(crate) async fn grant_queued(table: &SharedTable, cells: &[(usize, usize)]) {
+    for &pos in cells {
+        let mut guards = lock_scope(table, pos).await;
+
+        while guards[0].1.lock.is_none() {
+            let Some(client_id) = guards[0].1.waiters.pop_front() else {
+                break;
+            };
+
+            let held_by_other = guards.iter_mut()
+                .find(|(_, cell)| cell.lock.is_some_and(|lock| lock.owner_id != client_id));
+
+            if let Some((_, cell)) = held_by_other {
+                if !cell.waiters.contains(&client_id) {
+                    cell.waiters.push_back(client_id);
+                }
+                continue;
+            }
+
+            match check_claim(table, client_id, &guards).await {
+                Ok(()) => {
+                    apply_claim(table, client_id, &mut guards).await;
+                    table.sender.send(ServerSocketMessage::LockGranted{
+                        client_id,
+                        cell: pos
+                    }).ok();
+                },
+                Err(e) => {
+                    table.sender.send(ServerSocketMessage::LockDenied{
+                        client_id,
+                        cell: pos,
+                        code: e.code
+                    }).ok();
+                }
+            }
+        }
+    }
+}
+
+// Rejects edits to cells within a protected range the client is not allowed to edit
+fn check_cell_protection(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize)) -> Result<(), OperationError> {
+    if *session.role.borrow() == Role::Owner {
+        return Ok(());
+    }
+
+    match table.protected_ranges.iter().find(|range| range.contains((r, c)) && !range.permits(session.client_id)) {
+        Some(range) => Err(OperationError::new(
+            ErrorCode::Forbidden,
+            format!("cell ({}, {}) is within protected range {}", r, c, range.id)
+        )),
+        None => Ok(())
+    }
+}
+
+// Rejects deleting or moving rows/columns that intersect a protected range the client is not
+// allowed to edit
+fn check_span_protection(table: &SharedTable, session: &ClientSession, change: &StructuralChange) -> Result<(), OperationError> {
+    let (kind, start, count) = match *change {
+        StructuralChange::Delete { start, count, .. } => ("deletion", start, count),
+        StructuralChange::Move { from, count, .. } => ("move", from, count),
+        StructuralChange::Insert { .. } => return Ok(())
+    };
+
+    if *session.role.borrow() == Role::Owner {
+        return Ok(());
+    }
+
+    match table.protected_ranges.iter().find(|range| range.intersects(change.axis(), start, count) && !range.permits(session.client_id)) {
+        Some(range) => Err(OperationError::new(
+            ErrorCode::Forbidden,
+            format!("{} intersects protected range {}", kind, range.id)
+        )),
+        None => Ok(())
+    }
+}
+
+// Journals the text of a cell after an edit and marks the cell dirty. The edit must be neither
+// applied nor acknowledged, nor its cells locked, unless this succeeds.
+async fn record_edit(table: &SharedTable, session: &ClientSession, pos: (usize, usize), text_after: &str) -> Result<(), OperationError> {
+    if let Err(e) = table.journal.lock().await.append(pos, text_after).await {
+        error!("could not journal edit of cell {:?} of table {}: {}", pos, session.table_id, e);
+        return Err(OperationError::new(ErrorCode::StorageError, "the edit could not be saved and was not applied"));
+    }
+
+    table.dirty.lock().await.mark(pos);
+    Ok(())
+}
+
+async fn insert_text(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize), index: usize, text: String) -> Result<(), OperationError> {
+    let mut guards = lock_scope(table, (r, c)).await;
+
+    check_scope_owner(&guards, session.client_id)?;
+    check_cell_protection(table, session, (r, c))?;
+    let index = resolve_text_offset(&guards[0].1.text, index, session.offset_unit)?;
+
+    check_claim(table, session.client_id, &guards).await?;
+
+    let text_before: Arc<str> = Arc::from(guards[0].1.text.as_str());
+    let mut text_after = guards[0].1.text.clone();
+
+    text_after.insert_str(index, &text);
+    record_edit(table, session, (r, c), &text_after).await?;
+    apply_claim(table, session.client_id, &mut guards).await;
+    guards[0].1.text = text_after;
+
+    table.sender.send(ServerSocketMessage::Insert{
+        client_id: session.client_id,
+        cell: (r, c),
+        index,
+        text,
+        text_before
+    }).ok();
+
+    Ok(())
+}
+
+async fn delete_text(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize), start: usize, end: usize) -> Result<(), OperationError> {
+    let mut guards = lock_scope(table, (r, c)).await;
+
+    check_scope_owner(&guards, session.client_id)?;
+    check_cell_protection(table, session, (r, c))?;
+    let (start, end) = resolve_text_range(&guards[0].1.text, start, end, session.offset_unit)?;
+
+    check_claim(table, session.client_id, &guards).await?;
+
+    let text_before: Arc<str> = Arc::from(guards[0].1.text.as_str());
+    let mut text_after = guards[0].1.text.clone();
+
+    text_after.replace_range(start..end, "");
+    record_edit(table, session, (r, c), &text_after).await?;
+    apply_claim(table, session.client_id, &mut guards).await;
+    guards[0].1.text = text_after;
+
+    table.sender.send(ServerSocketMessage::Delete{
+        client_id: session.client_id,
+        cell: (r, c),
+        start,
+        end,
+        text_before
+    }).ok();
+
+    Ok(())
+}
+
+async fn replace_text(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize), start: usize, end: usize, text: String) -> Result<(), OperationError> {
+    let mut guards = lock_scope(table, (r, c)).await;
+
+    check_scope_owner(&guards, session.client_id)?;
+    check_cell_protection(table, session, (r, c))?;
+    let (start, end) = resolve_text_range(&guards[0].1.text, start, end, session.offset_unit)?;
+
+    check_claim(table, session.client_id, &guards).await?;
+
+    let text_before: Arc<str> = Arc::from(guards[0].1.text.as_str());
+    let mut text_after = guards[0].1.text.clone();
+
+    text_after.replace_range(start..end, &text);
+    record_edit(table, session, (r, c), &text_after).await?;
+    apply_claim(table, session.client_id, &mut guards).await;
+    guards[0].1.text = text_after;
+
+    table.sender.send(ServerSocketMessage::Replace{
+        client_id: session.client_id,
+        cell: (r, c),
+        start,
+        end,
+        text,
+        text_before
+    }).ok();
+
+    Ok(())
+}
+
+// Acquiring a lock the client already holds simply renews it
+async fn acquire_lock(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize)) -> Result<(), OperationError> {
+    let mut guards = lock_scope(table, (r, c)).await;
+
+    check_cell_protection(table, session, (r, c))?;
+    claim_lock(table, session, &mut guards).await
+}
+
+// Releasing cells the client does not hold is a no-op. Releasing a cell of a range lock releases
+// the whole range. A client queued for the cell leaves the queue instead. Pending edits are
+// written right away rather than at the next flush interval.
+async fn release_lock(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize)) -> Result<(), OperationError> {
+    let mut guards = lock_scope(table, (r, c)).await;
+    let mut released_ranges = HashSet::new();
+
+    for (_, cell) in guards.iter_mut() {
+        let n_waiters = cell.waiters.len();
+
+        cell.waiters.retain(|&waiter| waiter != session.client_id);
+        if cell.waiters.len() != n_waiters {
+            return Ok(());
+        }
+    }
+
+    check_scope_owner(&guards, session.client_id)?;
+
+    let mut queued = vec![];
+
+    for (pos, cell) in guards.iter_mut() {
+        match cell.lock {
+            Some(CellLockData { range_id: Some(range_id), .. }) => {
+                released_ranges.insert(range_id);
+            },
+            Some(_) => {
+                release_cell_lock(table, cell, *pos, &mut queued).await;
+            },
+            None => {}
+        }
+    }
+
+    // Range cells may lie anywhere in the table, including within the scope
+    drop(guards);
+    grant_queued(table, &queued).await;
+
+    if !released_ranges.is_empty() {
+        release_ranges(table, &released_ranges).await;
+    }
+
+    // Write the client's edits now that it is done with the cells
+    table.dirty.lock().await.flush_soon();
+
+    Ok(())
+}
+
+// === release_ranges =============================================================================
+//
+// Releases every cell of the given range locks as a unit, then hands the cells on to the clients
+// queued for them.
+//
+// ================================================================================================
+pub(crate) async fn release_ranges(table: &SharedTable, range_ids: &HashSet<RangeLockId>) {
+    let range_cells = table.locks.lock().await.remove_ranges(range_ids);
+    let mut queued = vec![];
+
+    for (i_row, i_col) in range_cells {
+        let mut cell = table.cells[i_row][i_col].lock().await;
+
+        release_cell_lock(table, &mut cell, (i_row, i_col), &mut queued).await;
+    }
+
+    grant_queued(table, &queued).await;
+}
+
+async fn renew_lock(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize)) -> Result<(), OperationError> {
+    let mut guards = lock_scope(table, (r, c)).await;
+
+    check_scope_owner(&guards, session.client_id)?;
+
+    if !is_held_by(&guards[0].1, session.client_id) {
+        return Err(OperationError::new(
+            ErrorCode::InvalidOperation,
+            format!("cell ({}, {}) is not locked; acquire the lock instead", r, c)
+        ));
+    }
+
+    claim_lock(table, session, &mut guards).await
+}
+
+async fn queue_lock(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize)) -> Result<(), OperationError> {
+    let mut guards = lock_scope(table, (r, c)).await;
+
+    check_cell_protection(table, session, (r, c))?;
+
+    let held_by_other = guards.iter()
+        .position(|(_, cell)| cell.lock.is_some_and(|lock| lock.owner_id != session.client_id));
+
+    let Some(i_held) = held_by_other else {
+        return claim_lock(table, session, &mut guards).await;
+    };
+
+    // A client that could not take the lock once granted may not queue for it either
+    if table.lock_policy.max_locks_per_client.is_some() {
+        check_lock_limit(table, count_held_locks(table, session.client_id, &guards).await)?;
+    }
+
+    let cell = &mut guards[i_held].1;
+
+    if !cell.waiters.contains(&session.client_id) {
+        cell.waiters.push_back(session.client_id);
+    }
+    Ok(())
+}
+
+// Releases whatever lock is held on the cell, on behalf of a table owner. Cells locked together
+// with it by the same holder (its row, or its range lock) are released as well.
+async fn break_lock(table: &SharedTable, session: &ClientSession, (r, c): (usize, usize)) -> Result<(), OperationError> {
+    if *session.role.borrow() != Role::Owner {
+        return Err(OperationError::new(ErrorCode::Forbidden, "only table owners may break locks"));
+    }
+
+    let mut guards = lock_scope(table, (r, c)).await;
+    let Some(holder_id) = guards[0].1.lock.map(|lock| lock.owner_id) else {
+        return Ok(());
+    };
+    let mut released_ranges = HashSet::new();
+
+    let mut queued = vec![];
+
+    info!("Client {} broke the lock of client {} on cell ({}, {})", session.client_id, holder_id, r, c);
+
+    for (pos, cell) in guards.iter_mut() {
+        match cell.lock {
+            Some(lock) if lock.owner_id != holder_id => {},
+            Some(CellLockData { range_id: Some(range_id), .. }) => {
+                released_ranges.insert(range_id);
+            },
+            Some(_) => {
+                release_cell_lock(table, cell, *pos, &mut queued).await;
+            },
+            None => {}
+        }
+    }
+
+    drop(guards);
+    grant_queued(table, &queued).await;
+
+    if !released_ranges.is_empty() {
+        release_ranges(table, &released_ranges).await;
+    }
+
+    Ok(())
+}
+
+// === release_client_locks =======================================================================
+//
+// Releases every lock held by the client (cell, range and exclusive locks), removes it from every
+// lock queue and has pending edits written right away. Used once the client's last connection
+// closes.
+//
+// ================================================================================================
+pub(crate) async fn release_client_locks(table: &mut SharedTable, session: &ClientSession) {
+    let (locked_cells, mut range_ids) = {
+        let schedule = table.locks.lock().await;
+
+        (schedule.locked_cells(), schedule.ranges_held_by(session.client_id))
+    };
+
+    let mut queued = vec![];
+
+    // Clients only ever queue for locked cells
+    for (i_row, i_col) in locked_cells {
+        let mut cell = table.cells[i_row][i_col].lock().await;
+
+        cell.waiters.retain(|&waiter| waiter != session.client_id);
+
+        match cell.lock {
+            Some(lock) if lock.owner_id != session.client_id => {},
+            Some(CellLockData { range_id: Some(range_id), .. }) => {
+                range_ids.insert(range_id);
+            },
+            Some(_) => {
+                release_cell_lock(table, &mut cell, (i_row, i_col), &mut queued).await;
+            },
+            None => {}
+        }
+    }
+
+    grant_queued(table, &queued).await;
+
+    if !range_ids.is_empty() {
+        release_ranges(table, &range_ids).await;
+    }
+
+    let held_scopes: Vec<ExclusiveScope> = table.exclusive_locks.iter()
+        .filter(|exclusive| exclusive.owner_id == session.client_id)
+        .map(|exclusive| exclusive.scope)
+        .collect();
+
+    for scope in held_scopes {
+        release_exclusive(table, session, scope).ok();
+    }
+
+    // Write the client's edits right away, in case the table is evicted or the server shut down
+    table.dirty.lock().await.flush_soon();
+}
+
+// === expire_locks ===============================================================================
+//
+// Releases every cell, range and exclusive lock whose deadline has passed. Returns the next
+// deadline, if any lock is still held.
+//
+// ================================================================================================
+pub(crate) async fn expire_locks(table: &mut SharedTable, table_id: TableId) -> Option<Instant> {
+    let now = Instant::now();
+    let sender = table.sender.clone();
+
+    table.exclusive_locks.retain(|exclusive| {
+        if exclusive.expires_at > now {
+            return true;
+        }
+        debug!("Releasing exclusive lock {:?} of client {}", exclusive.scope, exclusive.owner_id);
+        sender.send(ServerSocketMessage::ExclusiveReleased{
+            client_id: exclusive.owner_id,
+            scope: exclusive.scope,
+            broken_by: None
+        }).ok();
+        false
+    });
+
+    let (expired_cells, expired_ranges) = table.locks.lock().await.expired(now);
+
+    let mut queued = vec![];
+
+    for (i_row, i_col) in expired_cells {
+        let mut cell = table.cells[i_row][i_col].lock().await;
+
+        debug!("Releasing lock on cell ({}, {}) of table {}", i_row, i_col, table_id);
+        release_cell_lock(table, &mut cell, (i_row, i_col), &mut queued).await;
+    }
+
+    grant_queued(table, &queued).await;
+
+    if !expired_ranges.is_empty() {
+        debug!("Releasing range locks {:?} of table {}", expired_ranges, table_id);
+        release_ranges(table, &expired_ranges).await;
+    }
+
+    let next_exclusive = table.exclusive_locks.iter().map(|exclusive| exclusive.expires_at).min();
+    let next_lock = table.locks.lock().await.next_deadline();
+
+    next_exclusive.into_iter().chain(next_lock).min()
+}
+
+// Locks every cell of the rectangle, or none of them. Cells already locked by the client are
+// absorbed into the new range.
+async fn lock_range(table: &mut SharedTable, session: &ClientSession, top_left: (usize, usize), bottom_right: (usize, usize)) -> Result<(), OperationError> {
+    let range_id = table.next_range_id;
+
+    table.next_range_id += 1;
+
+    let table = &*table;
+
+    // Row granularity locks whole rows
+    let (top_left, bottom_right) = match table.lock_policy.granularity {
+        LockGranularity::Cell => (top_left, bottom_right),
+        LockGranularity::Row => ((top_left.0, 0), (bottom_right.0, table.n_cols - 1))
+    };
+
+    for i_row in top_left.0..=bottom_right.0 {
+        for i_col in top_left.1..=bottom_right.1 {
+            check_cell_protection(table, session, (i_row, i_col))?;
+        }
+    }
+
+    // Take the cell mutexes in row-major order; with the table lock held, no other operation can
+    // be locking cells concurrently
+    let mut guards: ScopeGuards<'_> = Vec::new();
+
+    for i_row in top_left.0..=bottom_right.0 {
+        for i_col in top_left.1..=bottom_right.1 {
+            guards.push(((i_row, i_col), table.cells[i_row][i_col].lock().await));
+        }
+    }
+
+    check_scope_owner(&guards, session.client_id)?;
+
+    if table.lock_policy.max_locks_per_client.is_some() {
+        check_lock_limit(table, count_held_locks(table, session.client_id, &guards).await)?;
+    }
+
+    let lock = CellLockData {
+        owner_id: session.client_id,
+        range_id: Some(range_id)
+    };
+    let mut schedule = table.locks.lock().await;
+
+    for (pos, cell) in guards.iter_mut() {
+        cell.lock = Some(lock);
+        schedule.schedule_cell(*pos, session.client_id, Lease::Range(range_id));
+    }
+
+    schedule.schedule_range(range_id, RangeLockData {
+        owner_id: session.client_id,
+        expires_at: table.lock_policy.lease_deadline()
+    });
+    drop(schedule);
+
+    table.sender.send(ServerSocketMessage::LockRange{
+        client_id: session.client_id,
+        range_id,
+        top_left,
+        bottom_right
+    }).ok();
+
+    Ok(())
+}
+
+// Takes sole editing rights over the scope. Fails if another client holds an overlapping exclusive
+// lock (unless a table owner forces it) or any cell lock within the scope.
+async fn acquire_exclusive(table: &mut SharedTable, session: &ClientSession, scope: ExclusiveScope, force: bool) -> Result<(), OperationError> {
+    if force && *session.role.borrow() != Role::Owner {
+        return Err(OperationError::new(ErrorCode::Forbidden, "only table owners may break exclusive locks"));
+    }
+
+    let is_conflict = |exclusive: &ExclusiveLock| exclusive.owner_id != session.client_id && exclusive.overlaps(scope);
+
+    if let Some(exclusive) = table.exclusive_locks.iter().find(|exclusive| is_conflict(exclusive)).filter(|_| !force) {
+        return Err(OperationError::new(
+            ErrorCode::LockConflict,
+            format!("{:?} is exclusively locked by client {}", exclusive.scope, exclusive.owner_id)
+        ));
+    }
+
+    if table.exclusive_locks.iter().any(|exclusive| exclusive.owner_id == session.client_id && exclusive.scope == scope) {
+        return Ok(());
+    }
+
+    // Cells locked by other clients would keep the holder from editing them. Only the cells the
+    // schedule knows to be locked are looked at, rather than every cell in the scope.
+    let rows = match scope {
+        ExclusiveScope::Table => 0..table.n_rows,
+        ExclusiveScope::Row { row } => row..(row + 1)
+    };
+
+    if let Some(((i_row, i_col), owner_id)) = table.locks.lock().await.held_by_other(session.client_id, rows) {
+        return Err(OperationError::new(
+            ErrorCode::LockConflict,
+            format!("cell ({}, {}) is locked by client {}", i_row, i_col, owner_id)
+        ));
+    }
+
+    // Break conflicting exclusive locks (only reached when forced)
+    let sender = table.sender.clone();
+
+    table.exclusive_locks.retain(|exclusive| {
+        if !is_conflict(exclusive) {
+            return true;
+        }
+        sender.send(ServerSocketMessage::ExclusiveReleased{
+            client_id: exclusive.owner_id,
+            scope: exclusive.scope,
+            broken_by: Some(session.client_id)
+        }).ok();
+        false
+    });
+
+    let exclusive = ExclusiveLock::new(session.client_id, scope, table.lock_policy.max_exclusive_secs);
+
+    table.exclusive_locks.push(exclusive);
+    // The lock manager releases the lock once it reaches its maximum hold time
+    table.locks.lock().await.wake();
+
+    let view = exclusive.view();
+
+    table.sender.send(ServerSocketMessage::ExclusiveAcquired{
+        client_id: view.client_id,
+        scope: view.scope,
+        expires_in_secs: view.expires_in_secs
+    }).ok();
+
+    Ok(())
+}
+
+// Releasing an exclusive lock the client does not hold is a no-op
+fn release_exclusive(table: &mut SharedTable, session: &ClientSession, scope: ExclusiveScope) -> Result<(), OperationError> {
+    let n_held = table.exclusive_locks.len();
+
+    table.exclusive_locks.retain(|exclusive| exclusive.owner_id != session.client_id || exclusive.scope != scope);
+
+    if table.exclusive_locks.len() != n_held {
+        table.sender.send(ServerSocketMessage::ExclusiveReleased{
+            client_id: session.client_id,
+            scope,
+            broken_by: None
+        }).ok();
+    }
+
+    Ok(())
+}
+
+// A statement of a structural change, with its parameters
+type Statement<'a> = (&'a str, &'a [&'a (dyn ToSql + Sync)]);
+
+// === commit_structural_change ===================================================================
+//
+// Writes the table's pending edits, then runs the statements of a structural change in a single
+// transaction, shifting the table's protected ranges along with them. Returns the shifted
+// protected ranges once the transaction has been committed. If anything fails, the transaction is
+// rolled back and the caller must leave the in-memory table untouched.
+//
+// ================================================================================================
+async fn commit_structural_change(table: &SharedTable, session: &ClientSession, change: &StructuralChange, statements: &[Statement<'_>]) -> Result<Vec<ProtectedRange>, OperationError> {
+    // The journal records cells by position, so it must be empty before positions change
+    if !flush_dirty_cells(table, &session.db, session.table_id).await {
+        return Err(OperationError::new(ErrorCode::StorageError, "pending edits could not be saved, so the change was not applied"));
+    }
+
+    let result = async {
+        let mut db_cli = session.db.client().await?;
+        let tx = db_cli.transaction().await?;
+
+        for (query, params) in statements {
+            tx.execute(*query, params).await?;
+        }
+
+        let protected_ranges = shift_protected_ranges(&tx, session.table_id, &table.protected_ranges, change).await?;
+
+        tx.commit().await?;
+        Ok::<_, DatabaseError>(protected_ranges)
+    }.await;
+
+    result.map_err(|e| {
+        error!("could not apply {:?} to table {}: {}", change, session.table_id, e);
+        OperationError::new(ErrorCode::StorageError, "the change could not be saved and was not applied")
+    })
+}
+
+// Makes position-keyed table state follow the cells through a structural change once it has been
+// committed. Row-wide exclusive locks on deleted rows are released.
+async fn apply_structural_change(table: &mut SharedTable, change: StructuralChange, protected_ranges: Vec<ProtectedRange>) {
+    table.protected_ranges = protected_ranges;
+    table.locks.lock().await.apply_change(&change);
+    table.dirty.lock().await.apply_change(&change);
+
+    if change.axis() != Axis::Rows {
+        return;
+    }
+
+    let sender = table.sender.clone();
+
+    table.exclusive_locks.retain_mut(|exclusive| {
+        let ExclusiveScope::Row { row } = &mut exclusive.scope else {
+            return true;
+        };
+
+        match change.map_index(*row) {
+            Some(new_row) => {
+                *row = new_row;
+                true
+            },
+            None => {
+                sender.send(ServerSocketMessage::ExclusiveReleased{
+                    client_id: exclusive.owner_id,
+                    scope: exclusive.scope,
+                    broken_by: None
+                }).ok();
+                false
+            }
+        }
+    });
+}
+
+// Tells clients about a committed structural change, followed by the protected ranges as they stand
+// after it
+fn broadcast_structural_change(table: &SharedTable, message: ServerSocketMessage) {
+    table.sender.send(message).ok();
+    table.sender.send(ServerSocketMessage::ProtectedRanges{
+        protected_ranges: table.protected_ranges.clone()
+    }).ok();
+}
+
+fn empty_cell() -> Arc<Mutex<TableCell>> {
+    Arc::new(Mutex::new(TableCell{
+        text: String::new(),
+        lock: None,
+        waiters: VecDeque::new()
+    }))
+}
+
+// Grows or shrinks the height/width of table $2 by $1
+fn resize_statement(dimension: &str, sign: char) -> String {
+    format!("UPDATE tables SET {dimension} = {dimension} {sign} $1 WHERE id = $2")
+}
+
+// Shifts the row/column numbers of table $1 from $3 onwards by $2, up or down. The primary key is
+// DEFERRABLE, so uniqueness is only checked once every key has been rewritten.
+fn shift_statement(column: &str, sign: char) -> String {
+    format!("UPDATE table_cells SET {column} = {column} {sign} $2 WHERE table_id = $1 AND {column} >= $3")
+}
+
+// Deletes the rows/columns [$2, $3) of table $1
+fn delete_statement(column: &str) -> String {
+    format!("DELETE FROM table_cells WHERE table_id = $1 AND {column} >= $2 AND {column} < $3")
+}
+
+// Creates the empty cells of rows [$2, $3) and columns [$4, $5) of table $1, once existing cells
+// have been shifted out of the way
+const INSERT_CELLS: &str = "INSERT INTO table_cells (table_id, row_num, column_num, text) \
+    SELECT $1, row_num, column_num, '' \
+    FROM generate_series($2::INTEGER, $3::INTEGER - 1) AS row_num, generate_series($4::INTEGER, $5::INTEGER - 1) AS column_num";
+
+async fn insert_rows(table: &mut SharedTable, session: &ClientSession, insertion_index: usize, num_rows: usize) -> Result<(), OperationError> {
+    let change = StructuralChange::Insert { axis: Axis::Rows, index: insertion_index, count: num_rows };
+    let (index, count, n_cols) = (insertion_index as i32, num_rows as i32, table.n_cols as i32);
+
+    let protected_ranges = commit_structural_change(table, session, &change, &[
+        (&resize_statement("height", '+'), &[&count, &session.table_id]),
+        (&shift_statement("row_num", '+'), &[&session.table_id, &count, &index]),
+        (INSERT_CELLS, &[&session.table_id, &index, &(index + count), &0i32, &n_cols])
+    ]).await?;
+
+    // Update table in-memory
+    table.n_rows += num_rows;
+
+    for _ in 0..num_rows {
+        table.cells.insert(insertion_index, (0..table.n_cols).map(|_| empty_cell()).collect());
+    }// end for _ in 0..num_rows
+
+    apply_structural_change(table, change, protected_ranges).await;
+
+    // Update clients
+    broadcast_structural_change(table, ServerSocketMessage::InsertRows{
+        client_id: session.client_id,
+        insertion_index,
+        num_rows
+    });
+
+    Ok(())
+}
+
+async fn insert_cols(table: &mut SharedTable, session: &ClientSession, insertion_index: usize, num_cols: usize) -> Result<(), OperationError> {
+    let change = StructuralChange::Insert { axis: Axis::Cols, index: insertion_index, count: num_cols };
+    let (index, count, n_rows) = (insertion_index as i32, num_cols as i32, table.n_rows as i32);
+
+    let protected_ranges = commit_structural_change(table, session, &change, &[
+        (&resize_statement("width", '+'), &[&count, &session.table_id]),
+        (&shift_statement("column_num", '+'), &[&session.table_id, &count, &index]),
+        (INSERT_CELLS, &[&session.table_id, &0i32, &n_rows, &index, &(index + count)])
+    ]).await?;
+
+    // Update table in-memory
+    table.n_cols += num_cols;
+
+    for row in table.cells.iter_mut() {
+        row.splice(insertion_index..insertion_index, (0..num_cols).map(|_| empty_cell()));
+    }// end for row in table.cells.iter_mut()
+
+    apply_structural_change(table, change, protected_ranges).await;
+
+    // Update clients
+    broadcast_structural_change(table, ServerSocketMessage::InsertCols{
+        client_id: session.client_id,
+        insertion_index,
+        num_cols
+    });
+
+    Ok(())
+}
+
+async fn delete_rows(table: &mut SharedTable, session: &ClientSession, start: usize, count: usize) -> Result<(), OperationError> {
+    let change = StructuralChange::Delete { axis: Axis::Rows, start, count };
+
+    check_span_protection(table, session, &change)?;
+
+    // Refuse to delete cells currently locked by another client
+    for (i_row, row) in table.cells[start..(start + count)].iter().enumerate() {
+        for (i_col, cell_ref) in row.iter().enumerate() {
+            check_cell_owner(&*cell_ref.lock().await, session.client_id, (start + i_row, i_col))?;
+        }
+    }
+
+    let (first, end, n_deleted) = (start as i32, (start + count) as i32, count as i32);
+
+    let protected_ranges = commit_structural_change(table, session, &change, &[
+        (&resize_statement("height", '-'), &[&n_deleted, &session.table_id]),
+        (&delete_statement("row_num"), &[&session.table_id, &first, &end]),
+        (&shift_statement("row_num", '-'), &[&session.table_id, &n_deleted, &end])
+    ]).await?;
+
+    // Update table in-memory
+    table.n_rows -= count;
+    table.cells.drain(start..(start + count));
+
+    apply_structural_change(table, change, protected_ranges).await;
+
+    // Update clients
+    broadcast_structural_change(table, ServerSocketMessage::DeleteRows{
+        client_id: session.client_id,
+        start,
+        count
+    });
+
+    Ok(())
+}
+
+async fn delete_cols(table: &mut SharedTable, session: &ClientSession, start: usize, count: usize) -> Result<(), OperationError> {
+    let change = StructuralChange::Delete { axis: Axis::Cols, start, count };
+
+    check_span_protection(table, session, &change)?;
+
+    // Refuse to delete cells currently locked by another client
+    for (i_row, row) in table.cells.iter().enumerate() {
+        for (i_col, cell_ref) in row[start..(start + count)].iter().enumerate() {
+            check_cell_owner(&*cell_ref.lock().await, session.client_id, (i_row, start + i_col))?;
+        }
+    }
+
+    let (first, end, n_deleted) = (start as i32, (start + count) as i32, count as i32);
+
+    let protected_ranges = commit_structural_change(table, session, &change, &[
+        (&resize_statement("width", '-'), &[&n_deleted, &session.table_id]),
+        (&delete_statement("column_num"), &[&session.table_id, &first, &end]),
+        (&shift_statement("column_num", '-'), &[&session.table_id, &n_deleted, &end])
+    ]).await?;
+
+    // Update table in-memory
+    table.n_cols -= count;
+
+    for row in table.cells.iter_mut() {
+        row.drain(start..(start + count));
+    }
+
+    apply_structural_change(table, change, protected_ranges).await;
+
+    // Update clients
+    broadcast_structural_change(table, ServerSocketMessage::DeleteCols{
+        client_id: session.client_id,
+        start,
+        count
+    });
+
+    Ok(())
+}
+
+// Rewrites the row/column numbers of the span affected by a move in a single statement. Keys
+// within the moved block shift by (to - from); keys displaced by the block shift by count in the
+// other direction.
+fn move_statement(column: &str) -> String {
+    format!(
+        "UPDATE table_cells SET {column} = {column} + CASE WHEN {column} >= $2 AND {column} < $3 THEN $4::INTEGER ELSE $5::INTEGER END \
+            WHERE table_id = $1 AND {column} >= $6 AND {column} < $7"
+    )
+}
+
+// Permutes a span of rows, or of the cells within a row, the way a move does
+fn rotate_span<T>(items: &mut [T], from: usize, count: usize, to: usize) {
+    let block = &mut items[from.min(to)..(from.max(to) + count)];
+
+    if to < from {
+        block.rotate_right(count);
+    } else {
+        block.rotate_left(count);
+    }
+}
+
+async fn move_rows(table: &mut SharedTable, session: &ClientSession, from: usize, count: usize, to: usize) -> Result<(), OperationError> {
+    if from == to {
+        return Ok(());
+    }
+
+    let change = StructuralChange::Move { axis: Axis::Rows, from, count, to };
+
+    check_span_protection(table, session, &change)?;
+
+    let (span_start, span_end) = (from.min(to) as i32, (from.max(to) + count) as i32);
+    let moved_shift = to as i32 - from as i32;
+    let displaced_shift = if to < from { count as i32 } else { -(count as i32) };
+
+    let protected_ranges = commit_structural_change(table, session, &change, &[
+        (&move_statement("row_num"), &[
+            &session.table_id,
+            &(from as i32), &((from + count) as i32),
+            &moved_shift, &displaced_shift,
+            &span_start, &span_end
+        ])
+    ]).await?;
+
+    // Permute the affected span; cell locks travel with their cells
+    rotate_span(&mut table.cells, from, count, to);
+
+    apply_structural_change(table, change, protected_ranges).await;
+
+    // Update clients
+    broadcast_structural_change(table, ServerSocketMessage::MoveRows{
+        client_id: session.client_id,
+        from,
+        count,
+        to
+    });
+
+    Ok(())
+}
+
+async fn move_cols(table: &mut SharedTable, session: &ClientSession, from: usize, count: usize, to: usize) -> Result<(), OperationError> {
+    if from == to {
+        return Ok(());
+    }
+
+    let change = StructuralChange::Move { axis: Axis::Cols, from, count, to };
+
+    check_span_protection(table, session, &change)?;
+
+    let (span_start, span_end) = (from.min(to) as i32, (from.max(to) + count) as i32);
+    let moved_shift = to as i32 - from as i32;
+    let displaced_shift = if to < from { count as i32 } else { -(count as i32) };
+
+    let protected_ranges = commit_structural_change(table, session, &change, &[
+        (&move_statement("column_num"), &[
+            &session.table_id,
+            &(from as i32), &((from + count) as i32),
+            &moved_shift, &displaced_shift,
+            &span_start, &span_end
+        ])
+    ]).await?;
+
+    // Permute the affected span of every row; cell locks travel with their cells
+    for row in table.cells.iter_mut() {
+        rotate_span(row, from, count, to);
+    }
+
+    apply_structural_change(table, change, protected_ranges).await;
+
+    // Update clients
+    broadcast_structural_change(table, ServerSocketMessage::MoveCols{
+        client_id: session.client_id,
+        from,
+        count,
+        to
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use tokio::sync::{Notify, broadcast};
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{
+        database::DatabaseConfig,
+        journal::Journal,
+        locking::{LockGranularity, LockPolicy, LockSchedule},
+        persistence::DirtyCells,
+        protection::{DELETE_PROTECTED_RANGE, INSERT_PROTECTED_RANGE, UPDATE_PROTECTED_RANGE}
+    };
+
+    // A resident 3x3 table journaling into its own directory; the database is never reached
+    async fn test_table(name: &str, lock_policy: LockPolicy) -> (SharedTableRef, Database, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("table-editor-operations-{}-{}", std::process::id(), name));
+        let journal = Journal::new(&dir).await.unwrap().open(1).await.unwrap();
+        let db = Database::new(&DatabaseConfig {
+            host: String::from("localhost"),
+            port: 5432,
+            user: String::from("u"),
+            dbname: String::from("d"),
+            password: String::from("p"),
+            pool_size: 1,
+            health_check_interval: std::time::Duration::from_secs(5),
+            tls: Default::default()
+        }).unwrap();
+        let (sender, _) = broadcast::channel(64);
+
+        let table = SharedTable {
+            n_rows: 3,
+            n_cols: 3,
+            cells: (0..3).map(|_| (0..3).map(|_| empty_cell()).collect()).collect(),
+            protected_ranges: vec![],
+            lock_policy,
+            locks: Mutex::new(LockSchedule::new(Arc::new(Notify::new()))),
+            flush: Arc::new(Mutex::new(())),
+            dirty: Arc::new(Mutex::new(DirtyCells::new(256, Arc::new(Notify::new())))),
+            journal: Arc::new(Mutex::new(journal)),
+            next_range_id: 0,
+            exclusive_locks: vec![],
+            client_count: 0,
+            client_sessions: HashMap::new(),
+            idle_since: None,
+            lifecycle: CancellationToken::new(),
+            sender
+        };
+
+        (Arc::new(Mutex::new(table)), db, dir)
+    }
+
+    fn session(table_ref: &SharedTableRef, db: &Database, client_id: u64, role: Role) -> ClientSession {
+        ClientSession {
+            client_id,
+            role: watch::channel(role).1,
+            offset_unit: OffsetUnit::Byte,
+            table_id: 1,
+            table_ref: Arc::clone(table_ref),
+            db: db.clone()
+        }
+    }
+
+    async fn code_of(session: &ClientSession, message: ClientSocketMessage) -> Option<ErrorCode> {
+        handle_client_message(session, message).await.err().map(|e| e.code)
+    }
+
+    async fn owner_of(table_ref: &SharedTableRef, (r, c): (usize, usize)) -> Option<u64> {
+        let table = table_ref.lock().await;
+        let cell = table.cells[r][c].lock().await;
+
+        cell.lock.map(|lock| lock.owner_id)
+    }
+
+    #[tokio::test]
+    async fn range_locks_reject_conflicts_and_excess_locks() {
+        let policy = LockPolicy { max_locks_per_client: Some(2), ..LockPolicy::default() };
+        let (table_ref, db, dir) = test_table("ranges", policy).await;
+        let (alice, bob) = (session(&table_ref, &db, 1, Role::Editor), session(&table_ref, &db, 2, Role::Editor));
+
+        assert_eq!(code_of(&alice, ClientSocketMessage::AcquireLock { cell: (1, 1) }).await, None);
+        assert_eq!(
+            code_of(&bob, ClientSocketMessage::LockRange { top_left: (0, 0), bottom_right: (1, 1) }).await,
+            Some(ErrorCode::LockConflict)
+        );
+        // Nothing of the rejected range stays locked
+        assert_eq!(owner_of(&table_ref, (0, 0)).await, None);
+
+        // A range counts as a single lock, however many cells it covers
+        assert_eq!(code_of(&bob, ClientSocketMessage::LockRange { top_left: (0, 0), bottom_right: (0, 2) }).await, None);
+        assert_eq!(code_of(&bob, ClientSocketMessage::AcquireLock { cell: (2, 0) }).await, None);
+        assert_eq!(
+            code_of(&bob, ClientSocketMessage::LockRange { top_left: (2, 1), bottom_right: (2, 2) }).await,
+            Some(ErrorCode::LockLimitExceeded)
+        );
+        assert_eq!(
+            code_of(&bob, ClientSocketMessage::AcquireLock { cell: (2, 2) }).await,
+            Some(ErrorCode::LockLimitExceeded)
+        );
+        assert_eq!(owner_of(&table_ref, (0, 2)).await, Some(2));
+        assert_eq!(owner_of(&table_ref, (2, 1)).await, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_waiter_gets_the_cell_on_release() {
+        let (table_ref, db, dir) = test_table("queue", LockPolicy::default()).await;
+        let (alice, bob) = (session(&table_ref, &db, 1, Role::Editor), session(&table_ref, &db, 2, Role::Editor));
+        let mut rx = table_ref.lock().await.sender.subscribe();
+
+        assert_eq!(code_of(&alice, ClientSocketMessage::AcquireLock { cell: (0, 1) }).await, None);
+        assert_eq!(code_of(&bob, ClientSocketMessage::QueueLock { cell: (0, 1) }).await, None);
+        assert_eq!(owner_of(&table_ref, (0, 1)).await, Some(1));
+
+        assert_eq!(code_of(&alice, ClientSocketMessage::ReleaseLock { cell: (0, 1) }).await, None);
+        assert_eq!(owner_of(&table_ref, (0, 1)).await, Some(2));
+
+        let mut granted = false;
+
+        while let Ok(message) = rx.try_recv() {
+            granted |= matches!(message, ServerSocketMessage::LockGranted { client_id: 2, cell: (0, 1) });
+        }
+        assert!(granted);
+
+        // The new holder may edit the cell; the previous one may not
+        assert_eq!(code_of(&bob, ClientSocketMessage::Insert { cell: (0, 1), index: 0, text: "b".into() }).await, None);
+        assert_eq!(
+            code_of(&alice, ClientSocketMessage::Insert { cell: (0, 1), index: 0, text: "a".into() }).await,
+            Some(ErrorCode::LockConflict)
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_grants_follow_the_lock_policy() {
+        let policy = LockPolicy { max_locks_per_client: Some(1), granularity: LockGranularity::Row, ..LockPolicy::default() };
+        let (table_ref, db, dir) = test_table("queue-policy", policy).await;
+        let alice = session(&table_ref, &db, 1, Role::Editor);
+        let bob = session(&table_ref, &db, 2, Role::Editor);
+        let carol = session(&table_ref, &db, 3, Role::Editor);
+        let mut rx = table_ref.lock().await.sender.subscribe();
+
+        assert_eq!(code_of(&alice, ClientSocketMessage::AcquireLock { cell: (0, 1) }).await, None);
+        assert_eq!(code_of(&bob, ClientSocketMessage::QueueLock { cell: (0, 0) }).await, None);
+        assert_eq!(code_of(&carol, ClientSocketMessage::QueueLock { cell: (0, 2) }).await, None);
+
+        // Bob uses up his limit while waiting, so the row skips him and goes to Carol as a whole
+        assert_eq!(code_of(&bob, ClientSocketMessage::AcquireLock { cell: (1, 0) }).await, None);
+        assert_eq!(code_of(&alice, ClientSocketMessage::ReleaseLock { cell: (0, 1) }).await, None);
+
+        for c in 0..3 {
+            assert_eq!(owner_of(&table_ref, (0, c)).await, Some(3));
+        }
+
+        let mut denied = false;
+
+        while let Ok(message) = rx.try_recv() {
+            denied |= matches!(
+                message,
+                ServerSocketMessage::LockDenied { client_id: 2, cell: (0, 0), code: ErrorCode::LockLimitExceeded }
+            );
+        }
+        assert!(denied);
+
+        // Clients at their limit cannot queue in the first place
+        assert_eq!(
+            code_of(&bob, ClientSocketMessage::QueueLock { cell: (0, 0) }).await,
+            Some(ErrorCode::LockLimitExceeded)
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn exclusive_locks_block_other_clients() {
+        let (table_ref, db, dir) = test_table("exclusive", LockPolicy::default()).await;
+        let alice = session(&table_ref, &db, 1, Role::Editor);
+        let bob = session(&table_ref, &db, 2, Role::Editor);
+        let owner = session(&table_ref, &db, 3, Role::Owner);
+
+        assert_eq!(code_of(&alice, ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Row { row: 1 }, force: false }).await, None);
+
+        // Edits within the row, and structural changes anywhere, are rejected before reaching the
+        // database
+        assert_eq!(
+            code_of(&bob, ClientSocketMessage::Replace { cell: (1, 2), start: 0, end: 0, text: "b".into() }).await,
+            Some(ErrorCode::LockConflict)
+        );
+        assert_eq!(code_of(&bob, ClientSocketMessage::DeleteRows { start: 2, count: 1 }).await, Some(ErrorCode::LockConflict));
+        assert_eq!(code_of(&bob, ClientSocketMessage::MoveCols { from: 0, count: 1, to: 2 }).await, Some(ErrorCode::LockConflict));
+        assert_eq!(
+            code_of(&bob, ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Table, force: false }).await,
+            Some(ErrorCode::LockConflict)
+        );
+        assert_eq!(code_of(&bob, ClientSocketMessage::Insert { cell: (0, 0), index: 0, text: "b".into() }).await, None);
+        assert_eq!(code_of(&alice, ClientSocketMessage::Insert { cell: (1, 2), index: 0, text: "a".into() }).await, None);
+
+        // Only owners may force their way in, which breaks the existing lock
+        assert_eq!(
+            code_of(&bob, ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Table, force: true }).await,
+            Some(ErrorCode::Forbidden)
+        );
+        // Cells locked by other clients block it as well
+        assert_eq!(
+            code_of(&owner, ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Table, force: true }).await,
+            Some(ErrorCode::LockConflict)
+        );
+        assert_eq!(code_of(&alice, ClientSocketMessage::ReleaseLock { cell: (1, 2) }).await, None);
+        assert_eq!(code_of(&bob, ClientSocketMessage::ReleaseLock { cell: (0, 0) }).await, None);
+        assert_eq!(code_of(&owner, ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Table, force: true }).await, None);
+
+        let table = table_ref.lock().await;
+
+        assert_eq!(table.exclusive_locks.len(), 1);
+        assert_eq!(table.exclusive_locks[0].owner_id, 3);
+        drop(table);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn departing_clients_release_locks_and_leave_queues() {
+        let (table_ref, db, dir) = test_table("departure", LockPolicy::default()).await;
+        let alice = session(&table_ref, &db, 1, Role::Editor);
+        let bob = session(&table_ref, &db, 2, Role::Editor);
+        let carol = session(&table_ref, &db, 3, Role::Editor);
+
+        assert_eq!(code_of(&alice, ClientSocketMessage::AcquireLock { cell: (2, 2) }).await, None);
+        assert_eq!(code_of(&alice, ClientSocketMessage::LockRange { top_left: (0, 0), bottom_right: (0, 1) }).await, None);
+        assert_eq!(code_of(&alice, ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Row { row: 1 }, force: false }).await, None);
+        assert_eq!(code_of(&bob, ClientSocketMessage::QueueLock { cell: (2, 2) }).await, None);
+        assert_eq!(code_of(&carol, ClientSocketMessage::QueueLock { cell: (2, 2) }).await, None);
+
+        // Bob leaves the queue without affecting anyone else
+        release_client_locks(&mut *table_ref.lock().await, &bob).await;
+        assert_eq!(owner_of(&table_ref, (2, 2)).await, Some(1));
+
+        // Once Alice leaves, the cell skips Bob and goes to Carol
+        release_client_locks(&mut *table_ref.lock().await, &alice).await;
+        assert_eq!(owner_of(&table_ref, (2, 2)).await, Some(3));
+        assert_eq!(owner_of(&table_ref, (0, 1)).await, None);
+
+        let table = table_ref.lock().await;
+
+        assert!(table.cells[2][2].lock().await.waiters.is_empty());
+        assert!(table.exclusive_locks.is_empty());
+        drop(table);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A fresh copy of the schema in its own Postgres schema, holding table 1 with 3x3 cells whose
+    // text is their original "row,column". Needs TABLE_EDITOR_TEST_DATABASE, a connection string
+    // such as "host=localhost user=postgres password=... dbname=...", and is skipped without it.
+    async fn test_schema(name: &str) -> Option<(tokio_postgres::Client, String)> {
+        let Ok(config) = std::env::var("TABLE_EDITOR_TEST_DATABASE") else {
+            eprintln!("TABLE_EDITOR_TEST_DATABASE is not set; skipping");
+            return None;
+        };
+        let (db_cli, connection) = tokio_postgres::connect(&config, tokio_postgres::NoTls).await.unwrap();
+        let schema = format!("operations_{}_{}", std::process::id(), name);
+
+        tokio::spawn(connection);
+        db_cli.batch_execute(&format!(
+            "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}; SET search_path TO {schema};"
+        )).await.unwrap();
+        db_cli.batch_execute(include_str!("../../Database/init-db.sql")).await.unwrap();
+        db_cli.batch_execute(
+            "INSERT INTO users (id, email, username, password_hashed) VALUES (1, 'u@example.com', 'u', '');
+            INSERT INTO tables (id, owner_id, name, time_created, width, height) VALUES (1, 1, 't', NOW(), 3, 3);
+            INSERT INTO table_cells (table_id, row_num, column_num, text)
+                SELECT 1, r, c, r || ',' || c FROM generate_series(0, 2) AS r, generate_series(0, 2) AS c;"
+        ).await.unwrap();
+
+        Some((db_cli, schema))
+    }
+
+    async fn cell_texts(db_cli: &tokio_postgres::Client) -> Vec<Vec<String>> {
+        let rows = db_cli.query("SELECT text FROM table_cells WHERE table_id = 1 ORDER BY row_num, column_num", &[]).await.unwrap();
+
+        rows.chunks(3).map(|row| row.iter().map(|cell| cell.get(0)).collect()).collect()
+    }
+
+    #[tokio::test]
+    async fn move_statements_permute_cells() {
+        let Some((db_cli, schema)) = test_schema("move").await else {
+            return;
+        };
+
+        // Row 0 to the end, then column 2 to the front, as move_rows/move_cols bind them
+        db_cli.execute(&move_statement("row_num"), &[&1i64, &0i32, &1i32, &2i32, &-1i32, &0i32, &3i32]).await.unwrap();
+        db_cli.execute(&move_statement("column_num"), &[&1i64, &2i32, &3i32, &-2i32, &1i32, &0i32, &3i32]).await.unwrap();
+
+        assert_eq!(cell_texts(&db_cli).await, vec![
+            vec!["1,2", "1,0", "1,1"],
+            vec!["2,2", "2,0", "2,1"],
+            vec!["0,2", "0,0", "0,1"]
+        ]);
+
+        db_cli.batch_execute(&format!("DROP SCHEMA {schema} CASCADE")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn structural_statements_prepare() {
+        let Some((db_cli, schema)) = test_schema("prepare").await else {
+            return;
+        };
+        let mut statements = vec![String::from(INSERT_CELLS)];
+
+        for (dimension, column) in [("height", "row_num"), ("width", "column_num")] {
+            for sign in ['+', '-'] {
+                statements.push(resize_statement(dimension, sign));
+                statements.push(shift_statement(column, sign));
+            }
+            statements.push(delete_statement(column));
+            statements.push(move_statement(column));
+        }
+        statements.extend([DELETE_PROTECTED_RANGE, UPDATE_PROTECTED_RANGE, INSERT_PROTECTED_RANGE].map(String::from));
+
+        for statement in &statements {
+            if let Err(e) = db_cli.prepare(statement).await {
+                panic!("could not prepare {}: {}", statement, e);
+            }
+        }
+
+        db_cli.batch_execute(&format!("DROP SCHEMA {schema} CASCADE")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn protected_rows_cannot_be_moved_or_deleted() {
+        let (table_ref, db, dir) = test_table("protected-moves", LockPolicy::default()).await;
+        let (alice, bob) = (session(&table_ref, &db, 1, Role::Editor), session(&table_ref, &db, 2, Role::Editor));
+
+        table_ref.lock().await.protected_ranges = vec![
+            ProtectedRange { id: 1, top_left: (1, 0), bottom_right: (1, 2), allowed_user_ids: vec![1] }
+        ];
+
+        assert_eq!(code_of(&bob, ClientSocketMessage::MoveRows { from: 1, count: 1, to: 2 }).await, Some(ErrorCode::Forbidden));
+        assert_eq!(code_of(&bob, ClientSocketMessage::MoveRows { from: 0, count: 2, to: 1 }).await, Some(ErrorCode::Forbidden));
+        assert_eq!(code_of(&bob, ClientSocketMessage::DeleteRows { start: 1, count: 1 }).await, Some(ErrorCode::Forbidden));
+        assert_eq!(code_of(&bob, ClientSocketMessage::MoveCols { from: 0, count: 1, to: 2 }).await, Some(ErrorCode::Forbidden));
+        // Permitted users get as far as the database, which this table never reaches
+        assert_eq!(code_of(&alice, ClientSocketMessage::MoveRows { from: 1, count: 1, to: 2 }).await, Some(ErrorCode::StorageError));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn split_protected_ranges_are_stored() {
+        let Some((mut db_cli, schema)) = test_schema("split").await else {
+            return;
+        };
+        let id: i64 = db_cli.query_one(
+            "INSERT INTO protected_ranges (table_id, top_row, left_col, bottom_row, right_col, allowed_user_ids) \
+                VALUES (1, 0, 0, 1, 2, '{7}') RETURNING id",
+            &[]
+        ).await.unwrap().get(0);
+        let range = ProtectedRange { id, top_left: (0, 0), bottom_right: (1, 2), allowed_user_ids: vec![7] };
+
+        // Row 0 goes to the end, leaving row 1 behind
+        let tx = db_cli.transaction().await.unwrap();
+        let shifted = shift_protected_ranges(&tx, 1, &[range], &StructuralChange::Move { axis: Axis::Rows, from: 0, count: 1, to: 2 }).await.unwrap();
+
+        tx.commit().await.unwrap();
+
+        let stored: Vec<(i64, i32, i32)> = db_cli.query("SELECT id, top_row, bottom_row FROM protected_ranges ORDER BY top_row", &[])
+            .await.unwrap().iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect();
+
+        assert_eq!(shifted.iter().map(|range| (range.top_left.0, range.bottom_right.0)).collect::<Vec<_>>(), vec![(0, 0), (2, 2)]);
+        assert_eq!(stored, vec![(id, 0, 0), (shifted[1].id, 2, 2)]);
+        assert!(shifted.iter().all(|range| range.allowed_user_ids == [7]));
+
+        db_cli.batch_execute(&format!("DROP SCHEMA {schema} CASCADE")).await.unwrap();
+    }
+}
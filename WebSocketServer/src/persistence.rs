@@ -0,0 +1,272 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    sync::Arc,
+    time::Duration
+};
+
+use futures::lock::{Mutex, OwnedMutexGuard};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use log::error;
+
+use crate::{
+    SharedTable,
+    SharedTableRef,
+    TableId,
+    database::{Database, DatabaseError, DatabaseStatus},
+    journal::TableJournal,
+    offsets::normalize_for_storage,
+    structure::{Axis, StructuralChange}
+};
+
+// === WriteBehindPolicy ==========================================================================
+//
+// How edited cells are written back to the database.
+//
+// - flush_interval: How long edits may wait before being written
+// - max_dirty_cells: How many edited cells may accumulate before they are written early
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WriteBehindPolicy {
+    pub(crate) flush_interval: Duration,
+    pub(crate) max_dirty_cells: usize
+}
+
+impl Default for WriteBehindPolicy {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_millis(500),
+            max_dirty_cells: 256
+        }
+    }
+}
+
+// === DirtyCells =================================================================================
+//
+// The cells of a table whose text has changed since it was last written to the database. Once
+// `max_dirty_cells` cells are dirty, the table's write-behind task is woken to write them early.
+//
+// ================================================================================================
+pub(crate) struct DirtyCells {
+    cells: HashSet<(usize, usize)>,
+    max_dirty_cells: usize,
+    wake: Arc<Notify>
+}
+
+impl DirtyCells {
+    pub(crate) fn new(max_dirty_cells: usize, wake: Arc<Notify>) -> Self {
+        Self { cells: HashSet::new(), max_dirty_cells, wake }
+    }
+
+    pub(crate) fn mark(&mut self, pos: (usize, usize)) {
+        if self.cells.insert(pos) && self.cells.len() == self.max_dirty_cells {
+            self.wake.notify_one();
+        }
+    }
+
+    pub(crate) fn take(&mut self) -> HashSet<(usize, usize)> {
+        std::mem::take(&mut self.cells)
+    }
+
+    // Wakes the write-behind task to write the dirty cells now rather than at the next interval
+    pub(crate) fn flush_soon(&self) {
+        if !self.cells.is_empty() {
+            self.wake.notify_one();
+        }
+    }
+
+    // Makes the tracker follow its cells through a structural change. Deleted cells need not be
+    // written.
+    pub(crate) fn apply_change(&mut self, change: &StructuralChange) {
+        self.cells = self.cells.drain()
+            .filter_map(|(r, c)| match change.axis() {
+                Axis::Rows => Some((change.map_index(r)?, c)),
+                Axis::Cols => Some((r, change.map_index(c)?))
+            })
+            .collect();
+    }
+}
+
+// A flush that has taken its snapshot of the dirty cells and is yet to write them. Holds the
+// table's flush lock until it completes.
+struct PendingFlush {
+    _flush: OwnedMutexGuard<()>,
+    cells: HashSet<(usize, usize)>,
+    texts: Vec<((usize, usize), String)>,
+    // The length of the journal when the snapshot was taken; entries beyond it are not covered
+    journal_len: u64,
+    dirty: Arc<Mutex<DirtyCells>>,
+    journal: Arc<Mutex<TableJournal>>
+}
+
+// Takes the table's flush lock and a snapshot of its dirty cells. Returns None if no cell is
+// dirty. Taking the flush lock waits for any flush still writing, so positions taken from the
+// table cannot be stale by the time they are written.
+async fn begin_flush(table: &SharedTable) -> Option<PendingFlush> {
+    let flush = Arc::clone(&table.flush).lock_owned().await;
+    let cells = table.dirty.lock().await.take();
+
+    if cells.is_empty() {
+        return None;
+    }
+
+    let mut texts = Vec::with_capacity(cells.len());
+
+    for &(i_row, i_col) in &cells {
+        texts.push(((i_row, i_col), table.cells[i_row][i_col].lock().await.text.clone()));
+    }
+
+    Some(PendingFlush {
+        _flush: flush,
+        cells,
+        texts,
+        journal_len: table.journal.lock().await.len(),
+        dirty: Arc::clone(&table.dirty),
+        journal: Arc::clone(&table.journal)
+    })
+}
+
+// Writes the snapshot, then discards the journal entries it covers. If either fails, the cells
+// are marked dirty again so the next flush retries them. Needs no lock on the table.
+async fn finish_flush(flush: PendingFlush, db: &Database, table_id: TableId) -> bool {
+    let n_cells = flush.cells.len();
+
+    // If the journal cannot be cut, the cells stay dirty so that a later flush cuts it instead
+    let result = async {
+        write_cells(db, table_id, flush.texts).await?;
+        flush.journal.lock().await.discard_through(flush.journal_len).await?;
+        Ok::<_, Box<dyn Error + Send + Sync>>(())
+    }.await;
+
+    if let Err(e) = result {
+        error!("could not write {} cells of table {}: {}", n_cells, table_id, e);
+
+        let mut dirty = flush.dirty.lock().await;
+
+        for pos in flush.cells {
+            dirty.mark(pos);
+        }
+        return false;
+    }
+
+    true
+}
+
+// === flush_dirty_cells ==========================================================================
+//
+// Writes the text of every dirty cell of the table in a single statement, then discards the
+// table's journal. If either fails, the cells are marked dirty again so the next flush retries
+// them. Returns whether every cell was written.
+//
+// The table stays locked throughout, so the journal is empty once this succeeds. Used before
+// structural changes, eviction and shutdown; the write-behind task flushes without blocking the
+// table instead.
+//
+// ================================================================================================
+pub(crate) async fn flush_dirty_cells(table: &SharedTable, db: &Database, table_id: TableId) -> bool {
+    match begin_flush(table).await {
+        Some(flush) => finish_flush(flush, db, table_id).await,
+        None => true
+    }
+}
+
+// === write_cells ================================================================================
+//
+// Sets the text of the given cells of a table in a single statement. Cells that do not exist are
+// skipped.
+//
+// ================================================================================================
+pub(crate) async fn write_cells(db: &Database, table_id: TableId, cells: impl IntoIterator<Item = ((usize, usize), String)>) -> Result<(), DatabaseError> {
+    let mut row_nums = vec![];
+    let mut column_nums = vec![];
+    let mut texts = vec![];
+
+    for ((i_row, i_col), text) in cells {
+        row_nums.push(i_row as i32);
+        column_nums.push(i_col as i32);
+        texts.push(normalize_for_storage(&text));
+    }
+
+    db.client().await?.execute(
+        "UPDATE table_cells AS cell SET text = dirty.text \
+            FROM UNNEST($2::INTEGER[], $3::INTEGER[], $4::TEXT[]) AS dirty(row_num, column_num, text) \
+            WHERE cell.table_id = $1 AND cell.row_num = dirty.row_num AND cell.column_num = dirty.column_num",
+        &[&table_id, &row_nums, &column_nums, &texts]
+    ).await?;
+
+    Ok(())
+}
+
+// === run_write_behind ===========================================================================
+//
+// Writes the dirty cells of a table to the database every flush interval, or as soon as enough
+// cells are dirty, regardless of which cells are locked. The task stops once the table is evicted;
+// eviction flushes the table itself.
+//
+// The table is only locked while the dirty cells are copied, so clients keep editing while the
+// database write is in flight. Nothing is attempted while the database is unavailable; the edits
+// stay in memory and in the journal until it is back.
+//
+// ================================================================================================
+pub(crate) async fn run_write_behind(table_ref: SharedTableRef, wake: Arc<Notify>, lifecycle: CancellationToken, policy: WriteBehindPolicy, table_id: TableId, db: Database) {
+    let mut interval = tokio::time::interval(policy.flush_interval);
+
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = lifecycle.cancelled() => break,
+            _ = interval.tick() => {},
+            _ = wake.notified() => {
+                interval.reset();
+            }
+        }
+
+        if *db.status().borrow() == DatabaseStatus::Unavailable {
+            continue;
+        }
+
+        let flush = begin_flush(&*table_ref.lock().await).await;
+
+        if let Some(flush) = flush {
+            finish_flush(flush, &db, table_id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn dirty_cells_follow_structural_changes() {
+        let wake = Arc::new(Notify::new());
+        let mut dirty = DirtyCells::new(3, Arc::clone(&wake));
+
+        dirty.mark((0, 0));
+        dirty.mark((1, 2));
+        dirty.mark((3, 1));
+        dirty.apply_change(&StructuralChange::Delete { axis: Axis::Rows, start: 1, count: 1 });
+        dirty.apply_change(&StructuralChange::Insert { axis: Axis::Cols, index: 1, count: 2 });
+
+        assert_eq!(dirty.take(), HashSet::from([(0, 0), (2, 3)]));
+        assert!(dirty.take().is_empty());
+    }
+
+    #[test]
+    fn flush_soon_wakes_the_writer_only_for_dirty_cells() {
+        let wake = Arc::new(Notify::new());
+        let mut dirty = DirtyCells::new(256, Arc::clone(&wake));
+
+        dirty.flush_soon();
+        assert!(wake.notified().now_or_never().is_none());
+
+        dirty.mark((1, 1));
+        dirty.flush_soon();
+        assert!(wake.notified().now_or_never().is_some());
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use tokio_postgres as postgres;
+use log::error;
+
+use crate::{
+    TableId,
+    structure::{Axis, StructuralChange}
+};
+
+// === ProtectedRange =============================================================================
+//
+// A rectangle of cells that only designated users may edit. Table owners may always edit
+// protected cells.
+//
+// - id: The id of the range in the protected_ranges table
+// - top_left: The (row, column) of the top left cell, inclusive
+// - bottom_right: The (row, column) of the bottom right cell, inclusive
+// - allowed_user_ids: The users, other than owners, who may edit cells within the range
+//
+// ================================================================================================
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ProtectedRange {
+    pub(crate) id: i64,
+    pub(crate) top_left: (usize, usize),
+    pub(crate) bottom_right: (usize, usize),
+    pub(crate) allowed_user_ids: Vec<u64>
+}
+
+impl ProtectedRange {
+    pub(crate) fn contains(&self, (r, c): (usize, usize)) -> bool {
+        (self.top_left.0..=self.bottom_right.0).contains(&r) && (self.top_left.1..=self.bottom_right.1).contains(&c)
+    }
+
+    // Whether the range overlaps the rows/columns [start, start + count) along `axis`
+    pub(crate) fn intersects(&self, axis: Axis, start: usize, count: usize) -> bool {
+        let (lo, hi) = match axis {
+            Axis::Rows => (self.top_left.0, self.bottom_right.0),
+            Axis::Cols => (self.top_left.1, self.bottom_right.1)
+        };
+
+        count > 0 && start <= hi && lo < start + count
+    }
+
+    pub(crate) fn permits(&self, user_id: u64) -> bool {
+        self.allowed_user_ids.contains(&user_id)
+    }
+
+    // Makes the range follow its cells through a structural change, returning the spans of rows or
+    // columns along the change's axis that hold its cells afterwards. Rows/columns inserted into the
+    // range's interior become protected as well. A move that carries part of the range elsewhere
+    // splits it into several spans rather than protecting everything in between. Returns no spans
+    // if every row or column of the range was deleted.
+    pub(crate) fn spans_after(&self, change: &StructuralChange) -> Vec<(usize, usize)> {
+        let (lo, hi) = match change.axis() {
+            Axis::Rows => (self.top_left.0, self.bottom_right.0),
+            Axis::Cols => (self.top_left.1, self.bottom_right.1)
+        };
+        let mut mapped: Vec<usize> = (lo..=hi).filter_map(|i| change.map_index(i)).collect();
+
+        mapped.sort_unstable();
+
+        if let StructuralChange::Insert { .. } = change {
+            return mapped.first().zip(mapped.last()).map(|(&min, &max)| (min, max)).into_iter().collect();
+        }
+
+        let mut spans: Vec<(usize, usize)> = vec![];
+
+        for i in mapped {
+            match spans.last_mut() {
+                Some((_, end)) if *end + 1 == i => *end = i,
+                _ => spans.push((i, i))
+            }
+        }
+        spans
+    }
+
+    // A copy of the range spanning [lo, hi] along `axis`
+    pub(crate) fn with_span(&self, axis: Axis, (lo, hi): (usize, usize)) -> ProtectedRange {
+        let mut range = self.clone();
+
+        match axis {
+            Axis::Rows => (range.top_left.0, range.bottom_right.0) = (lo, hi),
+            Axis::Cols => (range.top_left.1, range.bottom_right.1) = (lo, hi)
+        }
+        range
+    }
+}
+
+// === fetch_protected_ranges =====================================================================
+//
+// Loads the protected ranges of a table. Ranges with negative coordinates are skipped.
+//
+// ================================================================================================
+pub(crate) async fn fetch_protected_ranges(db_cli: &postgres::Client, table_id: TableId) -> Result<Vec<ProtectedRange>, postgres::Error> {
+    let rows = db_cli.query(
+        "SELECT id, top_row, left_col, bottom_row, right_col, allowed_user_ids FROM protected_ranges WHERE table_id = $1",
+        &[&table_id]
+    ).await?;
+    let mut ranges = vec![];
+
+    for row in rows {
+        let id: i64 = row.get(0);
+        let coords = [row.get::<_, i32>(1), row.get(2), row.get(3), row.get(4)].map(usize::try_from);
+        let allowed_user_ids: Vec<i64> = row.get(5);
+
+        let [Ok(top), Ok(left), Ok(bottom), Ok(right)] = coords else {
+            error!("protected range {} of table {} has invalid coordinates", id, table_id);
+            continue;
+        };
+
+        ranges.push(ProtectedRange {
+            id,
+            top_left: (top, left),
+            bottom_right: (bottom, right),
+            allowed_user_ids: allowed_user_ids.into_iter().filter_map(|uid| u64::try_from(uid).ok()).collect()
+        });
+    }
+
+    Ok(ranges)
+}
+
+pub(crate) const DELETE_PROTECTED_RANGE: &str = "DELETE FROM protected_ranges WHERE id = $1";
+pub(crate) const UPDATE_PROTECTED_RANGE: &str =
+    "UPDATE protected_ranges SET top_row = $2, left_col = $3, bottom_row = $4, right_col = $5 WHERE id = $1";
+
+pub(crate) const INSERT_PROTECTED_RANGE: &str =
+    "INSERT INTO protected_ranges (table_id, top_row, left_col, bottom_row, right_col, allowed_user_ids) \
+        VALUES ($1, $2, $3, $4, $5, $6) RETURNING id";
+
+// === shift_protected_ranges =====================================================================
+//
+// Shifts the protected ranges of a table through a structural change within the change's
+// transaction, returning the shifted ranges. Ranges whose cells were all deleted are removed, and
+// ranges split by a move keep their id for the first part and gain a new range for every other.
+// The caller installs the result once the transaction has been committed.
+//
+// ================================================================================================
+pub(crate) async fn shift_protected_ranges(tx: &postgres::Transaction<'_>, table_id: TableId, ranges: &[ProtectedRange], change: &StructuralChange) -> Result<Vec<ProtectedRange>, postgres::Error> {
+    let mut shifted = Vec::with_capacity(ranges.len());
+
+    for before in ranges {
+        let spans = before.spans_after(change);
+        let Some((&first, rest)) = spans.split_first() else {
+            tx.execute(DELETE_PROTECTED_RANGE, &[&before.id]).await?;
+            continue;
+        };
+        let range = before.with_span(change.axis(), first);
+
+        if range != *before {
+            let [top, left, bottom, right] = coords(&range);
+
+            tx.execute(UPDATE_PROTECTED_RANGE, &[&range.id, &top, &left, &bottom, &right]).await?;
+        }
+        shifted.push(range);
+
+        let allowed_user_ids: Vec<i64> = before.allowed_user_ids.iter().map(|&uid| uid as i64).collect();
+
+        for &span in rest {
+            let mut part = before.with_span(change.axis(), span);
+            let [top, left, bottom, right] = coords(&part);
+
+            part.id = tx.query_one(INSERT_PROTECTED_RANGE, &[&table_id, &top, &left, &bottom, &right, &allowed_user_ids]).await?.get(0);
+            shifted.push(part);
+        }
+    }
+
+    Ok(shifted)
+}
+
+// The top row, left column, bottom row and right column of a range, as stored
+fn coords(range: &ProtectedRange) -> [i32; 4] {
+    [range.top_left.0, range.top_left.1, range.bottom_right.0, range.bottom_right.1].map(|i| i as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range() -> ProtectedRange {
+        ProtectedRange { id: 1, top_left: (2, 1), bottom_right: (4, 1), allowed_user_ids: vec![7] }
+    }
+
+    #[test]
+    fn shifts_and_widens_with_insertions() {
+        let insert = |index| StructuralChange::Insert { axis: Axis::Rows, index, count: 2 };
+
+        assert_eq!(range().spans_after(&insert(0)), vec![(4, 6)]);
+        assert_eq!(range().spans_after(&insert(3)), vec![(2, 6)]);
+        assert_eq!(range().spans_after(&insert(5)), vec![(2, 4)]);
+
+        let left = range().with_span(Axis::Cols, range().spans_after(&StructuralChange::Insert { axis: Axis::Cols, index: 1, count: 1 })[0]);
+
+        assert_eq!((left.top_left, left.bottom_right), ((2, 2), (4, 2)));
+        assert!(left.contains((3, 2)) && !left.contains((3, 1)));
+    }
+
+    #[test]
+    fn shrinks_or_disappears_with_deletions() {
+        assert_eq!(range().spans_after(&StructuralChange::Delete { axis: Axis::Rows, start: 1, count: 2 }), vec![(1, 2)]);
+        assert!(range().spans_after(&StructuralChange::Delete { axis: Axis::Cols, start: 1, count: 1 }).is_empty());
+    }
+
+    #[test]
+    fn follows_moves_exactly() {
+        let moved = |from, count, to| range().spans_after(&StructuralChange::Move { axis: Axis::Rows, from, count, to });
+
+        // The whole range moves to the end of a 10-row table, protecting nothing in between
+        assert_eq!(moved(2, 3, 7), vec![(7, 9)]);
+        // Moving its middle row away splits it
+        assert_eq!(moved(3, 1, 9), vec![(2, 3), (9, 9)]);
+        // Rows moved past it shift it; rows moved into it split it rather than becoming protected
+        assert_eq!(moved(0, 1, 9), vec![(1, 3)]);
+        assert_eq!(moved(6, 2, 3), vec![(2, 2), (5, 6)]);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    sync::Arc,
+    time::Duration
+};
+use log::{error, info};
+
+use crate::{
+    SharedTablesMap,
+    TableId,
+    TableSlot,
+    database::Database,
+    persistence::flush_dirty_cells
+};
+
+// === ResidencyPolicy ============================================================================
+//
+// How long tables stay in memory once their last client disconnects.
+//
+// - idle_grace: How long a table without clients is kept before it is evicted, so that clients
+// reconnecting shortly after do not have to wait for it to be reloaded
+// - max_resident_tables: How many tables may be held in memory at once; beyond that, the tables
+// that have been idle the longest are evicted early. Tables with clients are never evicted, so the
+// cap may be exceeded while all resident tables are in use.
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ResidencyPolicy {
+    pub(crate) idle_grace: Duration,
+    pub(crate) max_resident_tables: usize
+}
+
+impl Default for ResidencyPolicy {
+    fn default() -> Self {
+        Self {
+            idle_grace: Duration::from_secs(60),
+            max_resident_tables: 256
+        }
+    }
+}
+
+// === claim_slot =================================================================================
+//
+// Returns the slot of a table, adding an empty one if the table has none yet. The map is only
+// locked for the lookup.
+//
+// ================================================================================================
+pub(crate) async fn claim_slot(shared_tables: &SharedTablesMap, table_id: TableId) -> TableSlot {
+    Arc::clone(shared_tables.lock().await.entry(table_id).or_default())
+}
+
+// === release_slot ===============================================================================
+//
+// Removes the slot of a table from the map if it holds no table and nobody else holds it. Slots
+// are only handed out under the map lock, so a slot the map alone refers to cannot be claimed
+// while it is being removed.
+//
+// ================================================================================================
+pub(crate) async fn release_slot(shared_tables: &SharedTablesMap, table_id: TableId) {
+    let mut tables = shared_tables.lock().await;
+    let unused = tables.get(&table_id).is_some_and(|slot| {
+        Arc::strong_count(slot) == 1 && slot.try_lock().is_some_and(|table_ref| table_ref.is_none())
+    });
+
+    if unused {
+        tables.remove(&table_id);
+    }
+}
+
+// === evict_table ================================================================================
+//
+// Flushes a table that has had no clients for at least the given time, stops its background tasks,
+// deletes its journal and removes it from the map. Returns false, leaving the table in place, if it
+// has not been idle long enough or its edits could not all be written.
+//
+// The table's slot is held throughout, so no client can join the table while it is being evicted;
+// the map is only locked to look the slot up and to remove it.
+//
+// ================================================================================================
+async fn evict_table(shared_tables: &SharedTablesMap, table_id: TableId, idle_for: Duration, db: &Database) -> bool {
+    let Some(slot) = shared_tables.lock().await.get(&table_id).map(Arc::clone) else {
+        return false;
+    };
+
+    {
+        let mut slot_guard = slot.lock().await;
+        let Some(table_ref) = slot_guard.as_ref().map(Arc::clone) else {
+            return false;
+        };
+        let table = table_ref.lock().await;
+
+        if table.idle_since.is_none_or(|idle_since| idle_since.elapsed() < idle_for) {
+            return false;
+        }
+
+        if !flush_dirty_cells(&table, db, table_id).await {
+            error!("keeping table {} in memory until its edits are written", table_id);
+            return false;
+        }
+        table.lifecycle.cancel();
+        table.journal.lock().await.remove().await;
+        *slot_guard = None;
+    }
+
+    drop(slot);
+    release_slot(shared_tables, table_id).await;
+
+    info!("Evicted table {} from memory", table_id);
+    true
+}
+
+// === enforce_resident_cap =======================================================================
+//
+// Evicts the tables that have been idle the longest until no more than the policy's maximum are
+// resident, or no idle tables are left. Tables that are being loaded or evicted meanwhile are
+// skipped.
+//
+// ================================================================================================
+pub(crate) async fn enforce_resident_cap(shared_tables: SharedTablesMap, policy: ResidencyPolicy, db: Database) {
+    let slots: Vec<(TableId, TableSlot)> = {
+        let tables = shared_tables.lock().await;
+
+        if tables.len() <= policy.max_resident_tables {
+            return;
+        }
+        tables.iter().map(|(table_id, slot)| (*table_id, Arc::clone(slot))).collect()
+    };
+
+    let mut idle_tables = vec![];
+
+    for (table_id, slot) in slots {
+        let Some(table_ref) = slot.try_lock().and_then(|table_ref| table_ref.as_ref().map(Arc::clone)) else {
+            continue;
+        };
+
+        let idle_since = table_ref.lock().await.idle_since;
+
+        if let Some(idle_since) = idle_since {
+            idle_tables.push((idle_since, table_id));
+        }
+    }
+
+    idle_tables.sort();
+
+    for (_, table_id) in idle_tables {
+        if shared_tables.lock().await.len() <= policy.max_resident_tables {
+            break;
+        }
+        evict_table(&shared_tables, table_id, Duration::ZERO, &db).await;
+    }
+}
+
+// === schedule_eviction ==========================================================================
+//
+// Evicts a table once it has had no clients for the policy's grace period. Does nothing if a client
+// connects in the meantime; the eviction is scheduled again when it leaves. Tables whose edits
+// cannot be written yet are retried after another grace period.
+//
+// ================================================================================================
+pub(crate) fn schedule_eviction(shared_tables: SharedTablesMap, table_id: TableId, policy: ResidencyPolicy, db: Database) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(policy.idle_grace).await;
+
+            let Some(slot) = shared_tables.lock().await.get(&table_id).map(Arc::clone) else {
+                return;
+            };
+            let Some(table_ref) = slot.lock().await.as_ref().map(Arc::clone) else {
+                return;
+            };
+            let idle_since = table_ref.lock().await.idle_since;
+
+            drop(slot);
+
+            // A client may have come and gone since, restarting the grace period
+            if idle_since.is_none_or(|idle_since| idle_since.elapsed() < policy.idle_grace) {
+                return;
+            }
+            if evict_table(&shared_tables, table_id, policy.idle_grace, &db).await {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn releases_only_unused_empty_slots() {
+        let shared_tables: SharedTablesMap = Arc::new(Mutex::new(Default::default()));
+
+        // Claimed elsewhere, e.g. by a connection still loading the table
+        let slot = claim_slot(&shared_tables, 1).await;
+        release_slot(&shared_tables, 1).await;
+        assert!(shared_tables.lock().await.contains_key(&1));
+
+        // Held while being loaded or evicted
+        drop(slot);
+        let slot_guard = shared_tables.lock().await[&1].try_lock_owned().unwrap();
+        release_slot(&shared_tables, 1).await;
+        assert!(shared_tables.lock().await.contains_key(&1));
+
+        drop(slot_guard);
+        release_slot(&shared_tables, 1).await;
+        assert!(!shared_tables.lock().await.contains_key(&1));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::task::TaskTracker;
+use log::{error, info};
+
+use crate::{
+    SharedTablesMap,
+    database::Database,
+    persistence::flush_dirty_cells
+};
+
+// === ShutdownPolicy =============================================================================
+//
+// How the server shuts down.
+//
+// - reconnect_after: How long clients are asked to wait before reconnecting to the restarted server
+// - deadline: How long shutdown may take before the server exits regardless; Docker kills the
+// process 10 seconds after sending SIGTERM
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ShutdownPolicy {
+    pub(crate) reconnect_after: Duration,
+    pub(crate) deadline: Duration
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        Self {
+            reconnect_after: Duration::from_secs(5),
+            deadline: Duration::from_secs(8)
+        }
+    }
+}
+
+// === shutdown_signal ============================================================================
+//
+// Completes once the process receives SIGTERM or SIGINT.
+//
+// ================================================================================================
+pub(crate) async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            error!("could not listen for SIGTERM: {}", e);
+            tokio::signal::ctrl_c().await.ok();
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT")
+    }
+}
+
+// === drain ======================================================================================
+//
+// Waits for every connection to close, then writes the dirty cells of every table to the
+// database and stops the tables' background tasks. Gives up once the policy's deadline has passed.
+//
+// Connections are expected to have been told to close already.
+//
+// ================================================================================================
+pub(crate) async fn drain(connections: TaskTracker, shared_tables: SharedTablesMap, db: &Database, policy: ShutdownPolicy) {
+    let drained = tokio::time::timeout(policy.deadline, async {
+        connections.close();
+        connections.wait().await;
+
+        let slots: Vec<_> = shared_tables.lock().await.iter()
+            .map(|(table_id, slot)| (*table_id, Arc::clone(slot)))
+            .collect();
+
+        for (table_id, slot) in slots {
+            let Some(table_ref) = slot.lock().await.as_ref().map(Arc::clone) else {
+                continue;
+            };
+            let table = table_ref.lock().await;
+
+            flush_dirty_cells(&table, db, table_id).await;
+            table.lifecycle.cancel();
+        }
+    }).await;
+
+    match drained {
+        Ok(()) => info!("All tables flushed"),
+        Err(_) => error!("shutdown did not complete within {:?}; exiting anyway", policy.deadline)
+    }
+}
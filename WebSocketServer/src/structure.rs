@@ -0,0 +1,89 @@
+// === StructuralChange ===========================================================================
+//
+// Describes how a structural operation rearranges the rows or columns of a table, so that state
+// keyed by cell position (protected ranges, locks, ...) can follow the cells it refers to.
+//
+// - Insert: `count` new rows/columns are inserted before `index`
+// - Delete: The rows/columns [start, start + count) are removed
+// - Move: The rows/columns [from, from + count) are moved so that the first ends up at `to`
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Axis {
+    Rows,
+    Cols,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StructuralChange {
+    Insert { axis: Axis, index: usize, count: usize },
+    Delete { axis: Axis, start: usize, count: usize },
+    Move { axis: Axis, from: usize, count: usize, to: usize },
+}
+
+impl StructuralChange {
+    pub(crate) fn axis(&self) -> Axis {
+        match *self {
+            StructuralChange::Insert { axis, .. }
+            | StructuralChange::Delete { axis, .. }
+            | StructuralChange::Move { axis, .. } => axis
+        }
+    }
+
+    // Maps a row/column index along the changed axis to its index after the change. Returns None
+    // if the row/column was deleted.
+    pub(crate) fn map_index(&self, i: usize) -> Option<usize> {
+        match *self {
+            StructuralChange::Insert { index, count, .. } => Some(if i >= index { i + count } else { i }),
+            StructuralChange::Delete { start, count, .. } => {
+                if i < start {
+                    Some(i)
+                } else if i < start + count {
+                    None
+                } else {
+                    Some(i - count)
+                }
+            },
+            StructuralChange::Move { from, count, to, .. } => Some(
+                if (from..(from + count)).contains(&i) {
+                    i - from + to
+                } else if to < from && (to..from).contains(&i) {
+                    i + count
+                } else if to > from && ((from + count)..(to + count)).contains(&i) {
+                    i - count
+                } else {
+                    i
+                }
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped(change: StructuralChange, len: usize) -> Vec<Option<usize>> {
+        (0..len).map(|i| change.map_index(i)).collect()
+    }
+
+    #[test]
+    fn maps_indices_across_insertions_and_deletions() {
+        let insert = StructuralChange::Insert { axis: Axis::Rows, index: 1, count: 2 };
+        let delete = StructuralChange::Delete { axis: Axis::Rows, start: 1, count: 2 };
+
+        assert_eq!(mapped(insert, 3), vec![Some(0), Some(3), Some(4)]);
+        assert_eq!(mapped(delete, 4), vec![Some(0), None, None, Some(1)]);
+    }
+
+    #[test]
+    fn maps_indices_across_moves() {
+        // [a b c d e] -> [a d e b c]
+        let forward = StructuralChange::Move { axis: Axis::Cols, from: 1, count: 2, to: 3 };
+        // [a b c d e] -> [a d e b c], expressed as moving d, e back to 1
+        let backward = StructuralChange::Move { axis: Axis::Cols, from: 3, count: 2, to: 1 };
+
+        assert_eq!(mapped(forward, 5), vec![Some(0), Some(3), Some(4), Some(1), Some(2)]);
+        assert_eq!(mapped(backward, 5), vec![Some(0), Some(3), Some(4), Some(1), Some(2)]);
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    fmt,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime}
+};
+
+use futures::Stream;
+use rustls::{
+    ClientConfig,
+    DigitallySignedStruct,
+    RootCertStore,
+    ServerConfig,
+    SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey
+};
+use serde::Deserialize;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tokio_util::sync::CancellationToken;
+use log::{debug, info, warn};
+
+// How often the certificate files of the TLS listener are checked for changes
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The cryptography used for every TLS connection of the server
+pub(crate) fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+// A TLS setting could not be applied; names the file involved, if any
+#[derive(Debug)]
+pub(crate) struct TlsError {
+    path: Option<PathBuf>,
+    message: String
+}
+
+impl TlsError {
+    fn new(path: &Path, message: impl fmt::Display) -> Self {
+        Self { path: Some(path.to_path_buf()), message: message.to_string() }
+    }
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}: {}", path.display(), self.message),
+            None => write!(f, "{}", self.message)
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        Self { path: None, message: e.to_string() }
+    }
+}
+
+// Reads every certificate in a PEM file
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem = std::fs::read(path).map_err(|e| TlsError::new(path, e))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, io::Error>>()
+        .map_err(|e| TlsError::new(path, e))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::new(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+// Reads the first private key in a PEM file
+pub(crate) fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let pem = std::fs::read(path).map_err(|e| TlsError::new(path, e))?;
+
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|e| TlsError::new(path, e))?
+        .ok_or_else(|| TlsError::new(path, "no private key found"))
+}
+
+// === SslMode ====================================================================================
+//
+// Whether the connection to the database is encrypted and authenticated, named after the
+// corresponding libpq settings.
+//
+// - disable: Plain text
+// - require: Encrypted, but the server's certificate is not checked
+// - verify-full: Encrypted, and the server's certificate must be signed by a trusted CA and
+// match the database host
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SslMode {
+    #[default]
+    Disable,
+    Require,
+    VerifyFull,
+}
+
+// === DatabaseTlsConfig ==========================================================================
+//
+// - sslmode: Whether and how the connection is secured
+// - sslrootcert: PEM bundle of the CAs trusted to sign the server's certificate under
+// verify-full; the system's trusted CAs if unset
+// - sslcert, sslkey: PEM certificate and key the server authenticates itself with, if the
+// database requires client certificates
+//
+// ================================================================================================
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DatabaseTlsConfig {
+    pub(crate) sslmode: SslMode,
+    pub(crate) sslrootcert: Option<PathBuf>,
+    pub(crate) sslcert: Option<PathBuf>,
+    pub(crate) sslkey: Option<PathBuf>
+}
+
+impl DatabaseTlsConfig {
+    // Builds the TLS configuration of database connections; None if they are not encrypted
+    pub(crate) fn client_config(&self) -> Result<Option<ClientConfig>, TlsError> {
+        let provider = crypto_provider();
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+
+        let builder = match self.sslmode {
+            SslMode::Disable => return Ok(None),
+            SslMode::Require => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(UnverifiedServer { provider })),
+            SslMode::VerifyFull => builder.with_root_certificates(self.root_store()?)
+        };
+
+        let config = match (&self.sslcert, &self.sslkey) {
+            (Some(cert_path), Some(key_path)) => {
+                builder.with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+                    .map_err(|e| TlsError::new(cert_path, e))?
+            },
+            _ => builder.with_no_client_auth()
+        };
+
+        Ok(Some(config))
+    }
+
+    fn root_store(&self) -> Result<RootCertStore, TlsError> {
+        let mut roots = RootCertStore::empty();
+
+        let certs = match &self.sslrootcert {
+            Some(path) => load_certs(path)?,
+            None => {
+                let native = rustls_native_certs::load_native_certs();
+
+                for e in native.errors {
+                    warn!("could not load a system CA certificate: {}", e);
+                }
+                native.certs
+            }
+        };
+        let (_, n_ignored) = roots.add_parsable_certificates(certs);
+
+        if n_ignored > 0 {
+            warn!("ignored {} invalid CA certificates", n_ignored);
+        }
+        if roots.is_empty() {
+            let path = self.sslrootcert.as_deref().unwrap_or(Path::new("system CA store"));
+            return Err(TlsError::new(path, "no usable CA certificates"));
+        }
+        Ok(roots)
+    }
+}
+
+// Accepts any server certificate, for sslmode=require. The handshake signatures are still checked,
+// so the connection is encrypted to whoever holds the certificate's key.
+#[derive(Debug)]
+struct UnverifiedServer {
+    provider: Arc<CryptoProvider>
+}
+
+impl ServerCertVerifier for UnverifiedServer {
+    fn verify_server_cert(&self, _: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// === ServerTlsConfig ============================================================================
+//
+// The certificate the server presents to clients when it serves wss:// itself.
+//
+// - cert: PEM certificate chain, leaf first
+// - key: PEM private key of the certificate
+//
+// ================================================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ServerTlsConfig {
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf
+}
+
+impl ServerTlsConfig {
+    fn certified_key(&self, provider: &CryptoProvider) -> Result<CertifiedKey, TlsError> {
+        CertifiedKey::from_der(load_certs(&self.cert)?, load_private_key(&self.key)?, provider)
+            .map_err(|e| TlsError::new(&self.key, e))
+    }
+
+    // When the certificate and key files were last modified, following symlinks
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+
+        Some((modified(&self.cert)?, modified(&self.key)?))
+    }
+}
+
+// === ReloadingCert ==============================================================================
+//
+// Presents the certificate of the TLS listener, reloading it whenever its files change so that
+// renewed certificates are picked up without a restart. Connections that are already open keep
+// the certificate they were established with.
+//
+// ================================================================================================
+#[derive(Debug)]
+pub(crate) struct ReloadingCert {
+    config: ServerTlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>
+}
+
+// Loads the certificate of the TLS listener, returning it along with the listener's configuration
+pub(crate) fn load_listener(config: ServerTlsConfig) -> Result<(Arc<ReloadingCert>, ServerConfig), TlsError> {
+    let provider = crypto_provider();
+    let certified_key = config.certified_key(&provider)?;
+    let cert = Arc::new(ReloadingCert {
+        config,
+        provider: Arc::clone(&provider),
+        current: RwLock::new(Arc::new(certified_key))
+    });
+
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::clone(&cert) as Arc<dyn ResolvesServerCert>);
+
+    // WebSocket upgrades require HTTP/1.1
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok((cert, server_config))
+}
+
+impl ReloadingCert {
+    // === run_reloader ===========================================================================
+    //
+    // Checks the certificate files every CERT_CHECK_INTERVAL until the server shuts down, and
+    // reloads them once either has changed. A certificate that cannot be loaded, e.g. because
+    // only one of the files has been replaced so far, is reported and the previous certificate
+    // kept until the files change again.
+    //
+    // ============================================================================================
+    pub(crate) async fn run_reloader(self: Arc<Self>, shutdown: CancellationToken) {
+        let mut last_modified = self.config.modified();
+        let mut interval = tokio::time::interval(CERT_CHECK_INTERVAL);
+
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            let modified = self.config.modified();
+
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            match self.config.certified_key(&self.provider) {
+                Ok(certified_key) => {
+                    *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(certified_key);
+                    info!("Reloaded TLS certificate from {}", self.config.cert.display());
+                },
+                Err(e) => warn!("could not reload TLS certificate, keeping the previous one -- {}", e)
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner())))
+    }
+}
+
+// === accept_tls =================================================================================
+//
+// Accepts connections on the listener and yields them once their TLS handshake has completed.
+// Handshakes run concurrently, so a slow client cannot hold up others; those that fail or take
+// longer than HANDSHAKE_TIMEOUT are dropped. Stops accepting once the stream is dropped.
+//
+// ================================================================================================
+pub(crate) fn accept_tls(listener: TcpListener, server_config: ServerConfig) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let (tx, rx) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                _ = tx.closed() => break,
+                accepted = listener.accept() => accepted
+            };
+            let (tcp_stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // E.g. out of file descriptors; back off rather than spin
+                    warn!("could not accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp_stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        tx.send(Ok(tls_stream)).await.ok();
+                    },
+                    Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer_addr, e),
+                    Err(_) => debug!("TLS handshake with {} timed out", peer_addr)
+                }
+            });
+        }
+    });
+
+    futures::stream::unfold(rx, |mut rx| async move {
+        let tls_stream = rx.recv().await?;
+        Some((tls_stream, rx))
+    })
+}
@@ -0,0 +1,410 @@
+use crate::{
+    ClientSocketMessage,
+    ErrorCode,
+    locking::ExclusiveScope,
+    offsets::{OffsetUnit, to_byte_offset},
+    operations::OperationError
+};
+
+// Upper bound on the number of rows or columns a single operation may insert. Keeps a single
+// message from allocating an unbounded number of cells.
+const MAX_STRUCTURAL_COUNT: usize = 1000;
+
+// Table dimensions are stored as Postgres INTEGERs.
+const MAX_TABLE_DIMENSION: usize = i32::MAX as usize;
+
+// === validate_message ===========================================================================
+//
+// Checks a client message against the current table dimensions before it is handed to an
+// operation handler. Every cell coordinate, row/column index and count is checked here, so the
+// handlers may index into the table directly.
+//
+// Text offsets depend on the contents of the target cell and are checked separately, with
+// resolve_text_offset and resolve_text_range, once the cell has been locked.
+//
+// ================================================================================================
+pub(crate) fn validate_message(message: &ClientSocketMessage, n_rows: usize, n_cols: usize) -> Result<(), OperationError> {
+    match *message {
+        ClientSocketMessage::Insert { cell, .. }
+            | ClientSocketMessage::Delete { cell, .. }
+            | ClientSocketMessage::Replace { cell, .. }
+            | ClientSocketMessage::AcquireLock { cell }
+            | ClientSocketMessage::ReleaseLock { cell }
+            | ClientSocketMessage::RenewLock { cell }
+            | ClientSocketMessage::QueueLock { cell }
+            | ClientSocketMessage::BreakLock { cell } => validate_cell(cell, n_rows, n_cols),
+        ClientSocketMessage::LockRange { top_left, bottom_right } => validate_cell_range(top_left, bottom_right, n_rows, n_cols),
+        ClientSocketMessage::AcquireExclusive { scope, .. }
+            | ClientSocketMessage::ReleaseExclusive { scope } => match scope {
+                ExclusiveScope::Table => Ok(()),
+                ExclusiveScope::Row { row } => validate_cell((row, 0), n_rows, n_cols)
+            },
+        ClientSocketMessage::InsertRows { insertion_index, num_rows } => validate_insertion("row", insertion_index, num_rows, n_rows),
+        ClientSocketMessage::InsertCols { insertion_index, num_cols } => validate_insertion("column", insertion_index, num_cols, n_cols),
+        ClientSocketMessage::DeleteRows { start, count } => validate_deletion("row", start, count, n_rows),
+        ClientSocketMessage::DeleteCols { start, count } => validate_deletion("column", start, count, n_cols),
+        ClientSocketMessage::MoveRows { from, count, to } => validate_move("row", from, count, to, n_rows),
+        ClientSocketMessage::MoveCols { from, count, to } => validate_move("column", from, count, to, n_cols)
+    }
+}
+
+pub(crate) fn validate_cell((r, c): (usize, usize), n_rows: usize, n_cols: usize) -> Result<(), OperationError> {
+    if r < n_rows && c < n_cols {
+        Ok(())
+    } else {
+        Err(OperationError::new(
+            ErrorCode::OutOfRange,
+            format!("cell ({}, {}) falls outside table of dimension {}x{}", r, c, n_rows, n_cols)
+        ))
+    }
+}
+
+// Checks that [top_left, bottom_right] describes a non-empty rectangle of cells within the table
+pub(crate) fn validate_cell_range(top_left: (usize, usize), bottom_right: (usize, usize), n_rows: usize, n_cols: usize) -> Result<(), OperationError> {
+    validate_cell(top_left, n_rows, n_cols)?;
+    validate_cell(bottom_right, n_rows, n_cols)?;
+
+    if top_left.0 > bottom_right.0 || top_left.1 > bottom_right.1 {
+        return Err(OperationError::new(
+            ErrorCode::InvalidRange,
+            format!("cell {:?} is not above and to the left of cell {:?}", top_left, bottom_right)
+        ));
+    }
+
+    Ok(())
+}
+
+// Converts a client text offset, expressed in `unit`, into a byte offset into `text`. Rejects
+// offsets that lie beyond the end of the text or fall inside a character.
+pub(crate) fn resolve_text_offset(text: &str, offset: usize, unit: OffsetUnit) -> Result<usize, OperationError> {
+    to_byte_offset(text, offset, unit).ok_or_else(|| OperationError::new(
+        ErrorCode::InvalidRange,
+        format!("offset {} ({:?}) is not a character boundary in cell text of length {}", offset, unit, text.len())
+    ))
+}
+
+// Converts a client text range, expressed in `unit`, into a byte range describing a valid
+// substring of `text`.
+pub(crate) fn resolve_text_range(text: &str, start: usize, end: usize, unit: OffsetUnit) -> Result<(usize, usize), OperationError> {
+    if start > end {
+        return Err(OperationError::new(
+            ErrorCode::InvalidRange,
+            format!("range start ({}) is greater than range end ({})", start, end)
+        ));
+    }
+
+    Ok((resolve_text_offset(text, start, unit)?, resolve_text_offset(text, end, unit)?))
+}
+
+fn validate_insertion(unit: &str, insertion_index: usize, count: usize, len: usize) -> Result<(), OperationError> {
+    if insertion_index > len {
+        return Err(OperationError::new(
+            ErrorCode::OutOfRange,
+            format!("insertion index ({}) > number of {}s ({})", insertion_index, unit, len)
+        ));
+    }
+
+    if count == 0 || count > MAX_STRUCTURAL_COUNT {
+        return Err(OperationError::new(
+            ErrorCode::InvalidOperation,
+            format!("cannot insert {} {}s; must be between 1 and {}", count, unit, MAX_STRUCTURAL_COUNT)
+        ));
+    }
+
+    if len + count > MAX_TABLE_DIMENSION {
+        return Err(OperationError::new(
+            ErrorCode::InvalidOperation,
+            format!("cannot grow table beyond {} {}s", MAX_TABLE_DIMENSION, unit)
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_deletion(unit: &str, start: usize, count: usize, len: usize) -> Result<(), OperationError> {
+    if start.checked_add(count).is_none_or(|end| end > len) {
+        return Err(OperationError::new(
+            ErrorCode::OutOfRange,
+            format!("cannot delete {} {}(s) at index {} from table with {} {}s", count, unit, start, len, unit)
+        ));
+    }
+
+    // The table must keep at least one row and column (CHECK (width > 0), CHECK (height > 0))
+    if count == 0 || count >= len {
+        return Err(OperationError::new(
+            ErrorCode::InvalidOperation,
+            format!("cannot delete {} {}(s) from table with {} {}s", count, unit, len, unit)
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_move(unit: &str, from: usize, count: usize, to: usize, len: usize) -> Result<(), OperationError> {
+    if from.checked_add(count).is_none_or(|end| end > len) || to.checked_add(count).is_none_or(|end| end > len) {
+        return Err(OperationError::new(
+            ErrorCode::OutOfRange,
+            format!("cannot move {} {}(s) from index {} to index {} in table with {} {}s", count, unit, from, to, len, unit)
+        ));
+    }
+
+    if count == 0 {
+        return Err(OperationError::new(
+            ErrorCode::InvalidOperation,
+            format!("cannot move zero {}s", unit)
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientRequest;
+
+    const N_ROWS: usize = 3;
+    const N_COLS: usize = 4;
+
+    fn code_of(message: ClientSocketMessage) -> Option<ErrorCode> {
+        validate_message(&message, N_ROWS, N_COLS).err().map(|e| e.code)
+    }
+
+    #[test]
+    fn accepts_cells_inside_table() {
+        for cell in [(0, 0), (N_ROWS - 1, N_COLS - 1)] {
+            assert_eq!(code_of(ClientSocketMessage::Insert { cell, index: 0, text: "a".into() }), None);
+            assert_eq!(code_of(ClientSocketMessage::Delete { cell, start: 0, end: 0 }), None);
+            assert_eq!(code_of(ClientSocketMessage::Replace { cell, start: 0, end: 0, text: "a".into() }), None);
+        }
+    }
+
+    #[test]
+    fn validates_cell_ranges() {
+        let lock_range = |top_left, bottom_right| code_of(ClientSocketMessage::LockRange { top_left, bottom_right });
+
+        assert_eq!(lock_range((0, 0), (N_ROWS - 1, N_COLS - 1)), None);
+        assert_eq!(lock_range((1, 1), (1, 1)), None);
+        assert_eq!(lock_range((0, 0), (N_ROWS, 0)), Some(ErrorCode::OutOfRange));
+        assert_eq!(lock_range((1, 0), (0, 2)), Some(ErrorCode::InvalidRange));
+        assert_eq!(lock_range((0, 2), (1, 1)), Some(ErrorCode::InvalidRange));
+    }
+
+    #[test]
+    fn rejects_cells_outside_table() {
+        for cell in [(999, 0), (0, 999), (N_ROWS, 0), (0, N_COLS), (usize::MAX, usize::MAX)] {
+            assert_eq!(
+                code_of(ClientSocketMessage::Insert { cell, index: 0, text: "a".into() }),
+                Some(ErrorCode::OutOfRange)
+            );
+            assert_eq!(
+                code_of(ClientSocketMessage::Delete { cell, start: 0, end: 1 }),
+                Some(ErrorCode::OutOfRange)
+            );
+            assert_eq!(
+                code_of(ClientSocketMessage::Replace { cell, start: 0, end: 1, text: "a".into() }),
+                Some(ErrorCode::OutOfRange)
+            );
+        }
+    }
+
+    #[test]
+    fn text_offsets_must_be_char_boundaries() {
+        let text = "h\u{e9}llo";// 'é' occupies bytes 1..3
+
+        assert!(resolve_text_offset(text, 0, OffsetUnit::Byte).is_ok());
+        assert!(resolve_text_offset(text, 1, OffsetUnit::Byte).is_ok());
+        assert!(resolve_text_offset(text, 3, OffsetUnit::Byte).is_ok());
+        assert!(resolve_text_offset(text, text.len(), OffsetUnit::Byte).is_ok());
+
+        assert_eq!(resolve_text_offset(text, 2, OffsetUnit::Byte).unwrap_err().code, ErrorCode::InvalidRange);
+        assert_eq!(resolve_text_offset(text, text.len() + 1, OffsetUnit::Byte).unwrap_err().code, ErrorCode::InvalidRange);
+        assert_eq!(resolve_text_offset(text, usize::MAX, OffsetUnit::Byte).unwrap_err().code, ErrorCode::InvalidRange);
+        assert_eq!(resolve_text_offset("\u{1f600}", 2, OffsetUnit::Byte).unwrap_err().code, ErrorCode::InvalidRange);
+        assert_eq!(resolve_text_offset("\u{1f600}", 1, OffsetUnit::Utf16).unwrap_err().code, ErrorCode::InvalidRange);
+        assert_eq!(resolve_text_offset(text, 4, OffsetUnit::Utf16).unwrap(), 5);
+    }
+
+    #[test]
+    fn text_ranges_must_be_ordered_and_in_bounds() {
+        let text = "h\u{e9}llo";
+
+        assert!(resolve_text_range(text, 0, text.len(), OffsetUnit::Byte).is_ok());
+        assert!(resolve_text_range(text, 3, 3, OffsetUnit::Byte).is_ok());
+        assert!(resolve_text_range("", 0, 0, OffsetUnit::Byte).is_ok());
+
+        for (start, end) in [(4, 3), (0, 2), (2, 4), (0, text.len() + 1), (usize::MAX, usize::MAX), (usize::MAX, 0)] {
+            assert_eq!(resolve_text_range(text, start, end, OffsetUnit::Byte).unwrap_err().code, ErrorCode::InvalidRange);
+        }
+    }
+
+    #[test]
+    fn insert_rows_and_cols_reject_bad_indices_and_counts() {
+        assert_eq!(code_of(ClientSocketMessage::InsertRows { insertion_index: N_ROWS, num_rows: 1 }), None);
+        assert_eq!(code_of(ClientSocketMessage::InsertCols { insertion_index: 0, num_cols: MAX_STRUCTURAL_COUNT }), None);
+
+        assert_eq!(
+            code_of(ClientSocketMessage::InsertRows { insertion_index: N_ROWS + 1, num_rows: 1 }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::InsertCols { insertion_index: usize::MAX, num_cols: 1 }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::InsertRows { insertion_index: 0, num_rows: 0 }),
+            Some(ErrorCode::InvalidOperation)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::InsertRows { insertion_index: 0, num_rows: usize::MAX }),
+            Some(ErrorCode::InvalidOperation)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::InsertCols { insertion_index: 0, num_cols: MAX_STRUCTURAL_COUNT + 1 }),
+            Some(ErrorCode::InvalidOperation)
+        );
+        assert_eq!(
+            validate_message(&ClientSocketMessage::InsertRows { insertion_index: 0, num_rows: 1 }, MAX_TABLE_DIMENSION, 1)
+                .unwrap_err().code,
+            ErrorCode::InvalidOperation
+        );
+    }
+
+    #[test]
+    fn delete_rows_and_cols_reject_bad_ranges() {
+        assert_eq!(code_of(ClientSocketMessage::DeleteRows { start: 1, count: N_ROWS - 1 }), None);
+        assert_eq!(code_of(ClientSocketMessage::DeleteCols { start: 0, count: N_COLS - 1 }), None);
+
+        assert_eq!(
+            code_of(ClientSocketMessage::DeleteRows { start: N_ROWS, count: 1 }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::DeleteCols { start: 2, count: N_COLS }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::DeleteRows { start: usize::MAX, count: usize::MAX }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::DeleteCols { start: 1, count: usize::MAX }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::DeleteRows { start: 0, count: 0 }),
+            Some(ErrorCode::InvalidOperation)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::DeleteRows { start: 0, count: N_ROWS }),
+            Some(ErrorCode::InvalidOperation)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::DeleteCols { start: 0, count: N_COLS }),
+            Some(ErrorCode::InvalidOperation)
+        );
+    }
+
+    #[test]
+    fn move_rows_and_cols_reject_bad_ranges() {
+        assert_eq!(code_of(ClientSocketMessage::MoveRows { from: 0, count: 1, to: N_ROWS - 1 }), None);
+        assert_eq!(code_of(ClientSocketMessage::MoveCols { from: 1, count: N_COLS - 1, to: 0 }), None);
+
+        assert_eq!(
+            code_of(ClientSocketMessage::MoveRows { from: N_ROWS, count: 1, to: 0 }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::MoveRows { from: 0, count: 1, to: N_ROWS }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::MoveCols { from: 0, count: N_COLS + 1, to: 0 }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::MoveCols { from: usize::MAX, count: 2, to: 0 }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::MoveRows { from: 0, count: usize::MAX, to: usize::MAX }),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::MoveCols { from: 0, count: 0, to: 1 }),
+            Some(ErrorCode::InvalidOperation)
+        );
+    }
+
+    #[test]
+    fn rejects_hostile_lock_messages() {
+        assert_eq!(
+            code_of(ClientSocketMessage::LockRange { top_left: (N_ROWS - 1, N_COLS - 1), bottom_right: (0, 0) }),
+            Some(ErrorCode::InvalidRange)
+        );
+        assert_eq!(
+            code_of(ClientSocketMessage::LockRange { top_left: (0, 0), bottom_right: (usize::MAX, usize::MAX) }),
+            Some(ErrorCode::OutOfRange)
+        );
+
+        for row in [N_ROWS, usize::MAX] {
+            assert_eq!(
+                code_of(ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Row { row }, force: true }),
+                Some(ErrorCode::OutOfRange)
+            );
+            assert_eq!(
+                code_of(ClientSocketMessage::ReleaseExclusive { scope: ExclusiveScope::Row { row } }),
+                Some(ErrorCode::OutOfRange)
+            );
+        }
+        assert_eq!(code_of(ClientSocketMessage::AcquireExclusive { scope: ExclusiveScope::Table, force: false }), None);
+
+        let cell_messages: [fn((usize, usize)) -> ClientSocketMessage; 5] = [
+            |cell| ClientSocketMessage::AcquireLock { cell },
+            |cell| ClientSocketMessage::ReleaseLock { cell },
+            |cell| ClientSocketMessage::RenewLock { cell },
+            |cell| ClientSocketMessage::QueueLock { cell },
+            |cell| ClientSocketMessage::BreakLock { cell }
+        ];
+
+        for message in cell_messages {
+            for cell in [(N_ROWS, 0), (0, N_COLS), (usize::MAX, usize::MAX)] {
+                assert_eq!(code_of(message(cell)), Some(ErrorCode::OutOfRange), "{:?}", message(cell));
+            }
+            assert_eq!(code_of(message((N_ROWS - 1, N_COLS - 1))), None);
+        }
+    }
+
+    #[test]
+    fn rejects_offsets_splitting_surrogate_pairs() {
+        let text = "a\u{1f600}b";// the emoji occupies UTF-16 units 1..3
+
+        assert_eq!(resolve_text_range(text, 1, 3, OffsetUnit::Utf16).unwrap(), (1, 5));
+
+        for (start, end) in [(2, 3), (0, 2), (2, 2), (2, usize::MAX)] {
+            assert_eq!(resolve_text_range(text, start, end, OffsetUnit::Utf16).unwrap_err().code, ErrorCode::InvalidRange);
+        }
+        assert_eq!(resolve_text_offset(text, 2, OffsetUnit::Utf16).unwrap_err().code, ErrorCode::InvalidRange);
+    }
+
+    #[test]
+    fn malformed_messages_fail_to_parse() {
+        for json in [
+            r#"{"type":"insert","cell":[-1,0],"index":0,"text":"a"}"#,
+            r#"{"type":"insert","cell":[0,0],"index":-1,"text":"a"}"#,
+            r#"{"type":"delete","cell":[0],"start":0,"end":1}"#,
+            r#"{"type":"replace","cell":[0,0],"start":0,"end":1}"#,
+            r#"{"type":"insert_rows","insertion_index":0,"num_rows":1e100}"#,
+            r#"{"type":"delete_cols","start":"0","count":1}"#,
+            r#"{"type":"move_rows","from":0,"count":1}"#,
+            r#"{"type":"lock_range","top_left":[0,0],"bottom_right":[-1,2]}"#,
+            r#"{"type":"acquire_exclusive","scope":{"kind":"column","column":0},"force":false}"#,
+            r#"{"type":"acquire_exclusive","scope":{"kind":"row"},"force":false}"#,
+            r#"{"type":"drop_table"}"#,
+            r#"{"cell":[0,0],"index":0,"text":"a"}"#,
+            r#"not json"#,
+        ] {
+            assert!(serde_json::from_str::<ClientRequest>(json).is_err(), "parsed hostile input {}", json);
+        }
+    }
+}